@@ -0,0 +1,86 @@
+//! Lookup timing on a generated 50k-rule SERVICEOWNERS, against a single
+//! `GlobSet` holding every rule (how the mapper matched before rules were
+//! partitioned by first segment).
+//!
+//! cargo run --release --example lookup_bench [RULES]
+
+use globset::{GlobBuilder, GlobSetBuilder};
+use serviceowners::ServiceMapper;
+use std::time::Instant;
+
+const LOOKUPS: usize = 20_000;
+/// The single set is far slower once rules need regexes; time fewer lookups
+const SINGLE_LOOKUPS: usize = 200;
+
+fn main() -> anyhow::Result<()> {
+    let rules: usize = match std::env::args().nth(1) {
+        Some(n) => n.parse()?,
+        None => 50_000,
+    };
+    let dirs = (rules / 100).max(1);
+    let mut content = String::new();
+    content.push_str("**/*.md docs\n**/Dockerfile platform\n");
+    for i in 0..rules {
+        // Every other rule needs the regex engine rather than a literal prefix
+        let tail = if i % 2 == 0 { "**" } else { "*/gen/*.rs" };
+        let (dir, svc) = (i % dirs, i / dirs);
+        content.push_str(&format!("team{}/svc{}/{} svc-{}\n", dir, svc, tail, i));
+    }
+    let paths: Vec<String> = (0..LOOKUPS)
+        .map(|i| {
+            let rule = i * 7919 % rules;
+            format!("team{}/svc{}/api/gen/lib.rs", rule % dirs, rule / dirs)
+        })
+        .collect();
+
+    let start = Instant::now();
+    let mapper = ServiceMapper::parse(&content)?;
+    let parse = start.elapsed();
+
+    let start = Instant::now();
+    let mut builder = GlobSetBuilder::new();
+    for line in content.lines() {
+        let pattern = line.split_whitespace().next().unwrap_or_default();
+        let glob = serviceowners::normalize_pattern(pattern)?;
+        builder.add(GlobBuilder::new(&glob).literal_separator(true).build()?);
+    }
+    let single = builder.build()?;
+    let single_build = start.elapsed();
+
+    let start = Instant::now();
+    let mut mapped = 0;
+    for path in &paths {
+        mapped += usize::from(mapper.find_service(path).is_some());
+    }
+    let single_expected = paths[..SINGLE_LOOKUPS]
+        .iter()
+        .filter(|p| mapper.find_service(p).is_some())
+        .count();
+    let partitioned = start.elapsed();
+
+    let start = Instant::now();
+    let mut single_mapped = 0;
+    for path in &paths[..SINGLE_LOOKUPS] {
+        single_mapped += usize::from(single.matches(path).into_iter().max().is_some());
+    }
+    let unpartitioned = start.elapsed();
+    assert_eq!(single_expected, single_mapped);
+
+    println!(
+        "{} rules ({} mapped of {} lookups)",
+        mapper.len(),
+        mapped,
+        LOOKUPS
+    );
+    println!(
+        "  partitioned: parse {:?}, {:?} per lookup",
+        parse,
+        partitioned / LOOKUPS as u32
+    );
+    println!(
+        "  single set:  build {:?}, {:?} per lookup",
+        single_build,
+        unpartitioned / SINGLE_LOOKUPS as u32
+    );
+    Ok(())
+}
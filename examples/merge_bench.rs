@@ -0,0 +1,69 @@
+//! Merge timing for SERVICEOWNERS fragments: `ServiceMapper::from_mappers`
+//! (one index build at the end) against folding them with `merge` (a build
+//! per step) and against parsing the concatenated text.
+//!
+//! cargo run --release --example merge_bench [FRAGMENTS] [RULES_PER_FRAGMENT]
+
+use serviceowners::ServiceMapper;
+use std::time::Instant;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let fragments: usize = match args.next() {
+        Some(n) => n.parse()?,
+        None => 100,
+    };
+    let rules: usize = match args.next() {
+        Some(n) => n.parse()?,
+        None => 200,
+    };
+    let sources: Vec<String> = (0..fragments)
+        .map(|f| {
+            let mut content = String::new();
+            for i in 0..rules {
+                let tail = if i % 2 == 0 { "**" } else { "*/gen/*.rs" };
+                content.push_str(&format!("team{}/svc{}/{} svc-{}-{}\n", f, i, tail, f, i));
+            }
+            content
+        })
+        .collect();
+
+    let start = Instant::now();
+    let parsed = sources
+        .iter()
+        .map(|s| ServiceMapper::parse(s))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let parse = start.elapsed();
+
+    let start = Instant::now();
+    let merged = ServiceMapper::from_mappers(parsed.clone())?;
+    let from_mappers = start.elapsed();
+
+    let start = Instant::now();
+    let mut folded = ServiceMapper::parse("")?;
+    for mapper in parsed {
+        folded = folded.merge(mapper)?;
+    }
+    let fold = start.elapsed();
+    assert_eq!(merged.len(), folded.len());
+
+    let start = Instant::now();
+    let whole = ServiceMapper::parse(&sources.concat())?;
+    let concat = start.elapsed();
+    assert_eq!(merged.len(), whole.len());
+
+    let probe = format!("team{}/svc1/api/gen/lib.rs", fragments - 1);
+    assert_eq!(merged.find_service(&probe), whole.find_service(&probe));
+
+    println!(
+        "{} fragments of {} rules ({} rules)",
+        fragments,
+        rules,
+        merged.len()
+    );
+    println!("  parse fragments:  {:?}", parse);
+    println!("  from_mappers:     {:?}", from_mappers);
+    println!("  merge one by one: {:?}", fold);
+    println!("  parse as one:     {:?}", concat);
+    Ok(())
+}
@@ -1,38 +1,633 @@
 use anyhow::{Context, Result};
-use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use globset::{Candidate, GlobBuilder, GlobSet, GlobSetBuilder};
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Newest services.yaml schema version; a file without `version` is version 1.
+pub const SERVICES_SCHEMA_VERSION: u32 = 2;
 
 /// Represents the content of services.yaml
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct ServicesFile {
-    pub services: HashMap<String, ServiceDef>,
+    /// Schema version; absent means 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+    /// Keyed by service name; sorted, so every listing is in a stable order
+    pub services: BTreeMap<String, ServiceDef>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default, JsonSchema)]
 pub struct ServiceDef {
     pub owners: Option<Vec<Owner>>,
     pub contact: Option<Contact>,
     pub docs: Option<String>,
     pub runbook: Option<String>,
+    /// Service is being sunset; changes to it should be questioned
+    #[serde(default)]
+    pub deprecated: bool,
+    /// Replacement service (implies `deprecated`)
+    pub deprecated_in_favor_of: Option<String>,
+    /// Former names that SERVICEOWNERS rules may still use
+    #[serde(default)]
+    pub aliases: Vec<String>,
+}
+
+impl ServiceDef {
+    pub fn is_deprecated(&self) -> bool {
+        self.deprecated || self.deprecated_in_favor_of.is_some()
+    }
+
+    /// `(field, value)` for each link field that is set.
+    pub fn links(&self) -> Vec<(&'static str, &str)> {
+        let mut out = Vec::new();
+        if let Some(v) = &self.docs {
+            out.push(("docs", v.as_str()));
+        }
+        if let Some(v) = &self.runbook {
+            out.push(("runbook", v.as_str()));
+        }
+        out
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// Offline check of a docs/runbook value: an http(s) URL or a repo-relative path.
+/// Returns the problem, if any.
+#[cfg(feature = "links")]
+pub fn check_link(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Some("is empty".to_string());
+    }
+    if value.contains(char::is_whitespace) {
+        return Some(format!("'{}' contains whitespace", value));
+    }
+    if value.contains("://") {
+        return match url::Url::parse(value) {
+            Ok(u) if u.scheme() != "http" && u.scheme() != "https" => Some(format!(
+                "'{}' has unexpected scheme '{}' (expected http or https)",
+                value,
+                u.scheme()
+            )),
+            Ok(u)
+                if u.host_str()
+                    .is_none_or(|h| !h.contains('.') && h != "localhost") =>
+            {
+                Some(format!("'{}' has no valid host", value))
+            }
+            Ok(_) => None,
+            Err(e) => Some(format!("'{}' is not a valid URL ({})", value, e)),
+        };
+    }
+    let first = value.split('/').next().unwrap_or(value);
+    if first.starts_with("www.")
+        || (value.contains('/') && first.contains('.') && first != "." && first != "..")
+    {
+        return Some(format!(
+            "'{}' looks like a URL without a scheme (add https://)",
+            value
+        ));
+    }
+    None
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 #[serde(untagged)]
 pub enum Owner {
-    Team { team: String },
-    User { user: String },
-    Email { email: String },
+    Team {
+        team: String,
+        /// Slack user-group id (`S0123ABC`) for `--owners-format slack`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slack: Option<String>,
+    },
+    User {
+        user: String,
+        /// Slack member id (`U0123ABC`) for `--owners-format slack`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        slack: Option<String>,
+    },
+    Email {
+        email: String,
+    },
     // Fallback for simple strings if any
     Raw(String),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+/// How owners are written out when displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum OwnerFormat {
+    /// GitHub mentions: `@org/team`, `@user`
+    Mention,
+    /// Names without the leading `@`, for logs
+    Plain,
+    /// Slack handles (`<!subteam^ID>`, `<@ID>`) where a `slack` id is set, otherwise plain
+    Slack,
+}
+
+/// Options controlling how services.yaml is loaded
+#[derive(Debug, Clone, Default)]
+pub struct ServicesOptions {
+    /// Expand `${VAR}` in string values from the environment (`$${` is a literal `${`).
+    pub expand_env: bool,
+}
+
+impl ServicesFile {
+    /// Loads either a single services file or a directory of `*.yaml`/`*.yml` fragments.
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::load_with(path, &ServicesOptions::default())
+    }
+
+    pub fn load_with(path: &Path, opts: &ServicesOptions) -> Result<Self> {
+        if path.is_dir() {
+            Self::from_dir_with(path, opts)
+        } else {
+            Self::from_file_with(path, opts)
+        }
+    }
+
+    /// Merges every `*.yaml`/`*.yml` file in `dir` (non-recursive, in name order).
+    /// A service defined in more than one file is an error.
+    pub fn from_dir(dir: &Path) -> Result<Self> {
+        Self::from_dir_with(dir, &ServicesOptions::default())
+    }
+
+    pub fn from_dir_with(dir: &Path, opts: &ServicesOptions) -> Result<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("Failed to read services directory at {:?}", dir))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| {
+                p.is_file()
+                    && matches!(p.extension().and_then(|e| e.to_str()), Some("yaml" | "yml"))
+            })
+            .collect();
+        paths.sort();
+
+        let mut services = BTreeMap::new();
+        let mut origins: HashMap<String, PathBuf> = HashMap::new();
+        for path in paths {
+            let file = Self::from_file_with(&path, opts)?;
+            for (name, def) in file.services {
+                if let Some(first) = origins.get(&name) {
+                    anyhow::bail!(
+                        "Service '{}' is defined in both {:?} and {:?}",
+                        name,
+                        first,
+                        path
+                    );
+                }
+                origins.insert(name.clone(), path.clone());
+                services.insert(name, def);
+            }
+        }
+        Ok(Self {
+            version: None,
+            services,
+        })
+    }
+
+    /// Looks up a service by name or alias.
+    pub fn get(&self, name: &str) -> Option<&ServiceDef> {
+        self.resolve(name).map(|(_, def)| def)
+    }
+
+    /// Canonical name and definition for a service name or alias. A direct
+    /// key always takes precedence over an alias.
+    pub fn resolve(&self, name: &str) -> Option<(&str, &ServiceDef)> {
+        if let Some((key, def)) = self.services.get_key_value(name) {
+            return Some((key.as_str(), def));
+        }
+        self.services
+            .iter()
+            .find(|(_, def)| def.aliases.iter().any(|a| a == name))
+            .map(|(key, def)| (key.as_str(), def))
+    }
+
+    /// Canonical name for `name`, or `name` itself if it isn't declared.
+    pub fn canonical_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.resolve(name).map(|(key, _)| key).unwrap_or(name)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_file_with(path, &ServicesOptions::default())
+    }
+
+    pub fn from_file_with(path: &Path, opts: &ServicesOptions) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read services file at {:?}", path))?;
+        let parsed: Self = serde_yaml::from_str(&content).map_err(|err| {
+            // `path:line:column` so editors and CI logs can jump to the spot
+            let location = match err.location() {
+                Some(loc) => format!("{}:{}:{}", path.display(), loc.line(), loc.column()),
+                None => path.display().to_string(),
+            };
+            anyhow::Error::new(err)
+                .context(format!("Failed to parse services file at {}", location))
+        })?;
+        if let Some(version) = parsed.version {
+            if version == 0 || version > SERVICES_SCHEMA_VERSION {
+                anyhow::bail!(
+                    "Unsupported services file version {} in {:?}; versions 1 to {} are supported",
+                    version,
+                    path,
+                    SERVICES_SCHEMA_VERSION
+                );
+            }
+        }
+        if !opts.expand_env || !content.contains('$') {
+            return Ok(parsed);
+        }
+        // The typed parse above already validated the shape (with locations);
+        // expand on the raw tree so every string field is covered.
+        let mut value: serde_yaml::Value = serde_yaml::from_str(&content)?;
+        expand_env_values(&mut value, &mut String::new())
+            .with_context(|| format!("Failed to expand variables in {:?}", path))?;
+        serde_yaml::from_value(value)
+            .with_context(|| format!("Failed to parse services file at {:?}", path))
+    }
+}
+
+/// Keys serde reads at each level of services.yaml; anything else is ignored
+/// on load, which `ServicesFile::validate_source` reports.
+const SERVICES_FILE_KEYS: &[&str] = &["version", "services"];
+const SERVICE_DEF_KEYS: &[&str] = &[
+    "owners",
+    "contact",
+    "docs",
+    "runbook",
+    "deprecated",
+    "deprecated_in_favor_of",
+    "aliases",
+];
+const CONTACT_KEYS: &[&str] = &["slack", "email", "pagerduty", "oncall_url"];
+const OWNER_KEYS: &[&str] = &["team", "user", "email", "slack"];
+
+impl ServicesFile {
+    /// Every problem in services.yaml text: `invalid-yaml`, `unknown-key` and
+    /// `invalid-file` errors.
+    pub fn validate_source(content: &str) -> Vec<Diagnostic> {
+        let error = |line, code, message| Diagnostic {
+            line,
+            severity: Severity::Error,
+            code,
+            pattern: None,
+            message,
+        };
+        let value: serde_yaml::Value = match serde_yaml::from_str(content) {
+            Ok(value) => value,
+            Err(err) => {
+                let line = err.location().map(|loc| loc.line());
+                return vec![error(line, "invalid-yaml", err.to_string())];
+            }
+        };
+        let mut unknown = Vec::new();
+        unknown_keys(&value, SERVICES_FILE_KEYS, &[], &mut unknown);
+        let services = value.get("services").and_then(|v| v.as_mapping());
+        for (name, def) in services.into_iter().flatten() {
+            let path = vec!["services".to_string(), yaml_key(name)];
+            let child = |key: &str| [path.as_slice(), &[key.to_string()]].concat();
+            unknown_keys(def, SERVICE_DEF_KEYS, &path, &mut unknown);
+            if let Some(contact) = def.get("contact") {
+                unknown_keys(contact, CONTACT_KEYS, &child("contact"), &mut unknown);
+            }
+            let owners = def.get("owners").and_then(|v| v.as_sequence());
+            for owner in owners.into_iter().flatten() {
+                unknown_keys(owner, OWNER_KEYS, &child("owners"), &mut unknown);
+            }
+        }
+        let mut diags: Vec<Diagnostic> = unknown
+            .into_iter()
+            .map(|(path, key, expected)| {
+                let within = [path.as_slice(), std::slice::from_ref(&key)].concat();
+                let location = if path.is_empty() {
+                    "at the top level".to_string()
+                } else {
+                    format!("in {}", path.join("."))
+                };
+                let message = format!(
+                    "Unknown key '{}' {}; expected one of: {}.",
+                    key,
+                    location,
+                    expected.join(", ")
+                );
+                error(key_line(content, &within), "unknown-key", message)
+            })
+            .collect();
+        if let Err(err) = serde_yaml::from_value::<ServicesFile>(value) {
+            // Locations come from the text parse; the tree parse has none
+            let line = serde_yaml::from_str::<ServicesFile>(content)
+                .err()
+                .and_then(|e| e.location().map(|loc| loc.line()));
+            diags.push(error(line, "invalid-file", err.to_string()));
+        }
+        diags.sort_by_key(|d| d.line);
+        diags
+    }
+}
+
+/// Records `(parent path, key, expected)` for each key of mapping `value` that
+/// isn't in `expected`. Non-mappings are left to the typed parse.
+fn unknown_keys(
+    value: &serde_yaml::Value,
+    expected: &'static [&'static str],
+    path: &[String],
+    out: &mut Vec<(Vec<String>, String, &'static [&'static str])>,
+) {
+    let Some(mapping) = value.as_mapping() else {
+        return;
+    };
+    for key in mapping.keys() {
+        let key = yaml_key(key);
+        if !expected.contains(&key.as_str()) {
+            out.push((path.to_vec(), key, expected));
+        }
+    }
+}
+
+fn yaml_key(key: &serde_yaml::Value) -> String {
+    match key.as_str() {
+        Some(s) => s.to_string(),
+        None => serde_yaml::to_string(key)
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default(),
+    }
+}
+
+/// 1-based line of the block-style key at `path`; `None` for flow style.
+fn key_line(content: &str, path: &[String]) -> Option<usize> {
+    let mut lines = content.lines().enumerate();
+    let mut found = None;
+    for key in path {
+        let (idx, _) = lines.by_ref().find(|(_, line)| {
+            let item = line.trim_start().trim_start_matches("- ");
+            [key.clone(), format!("\"{}\"", key), format!("'{}'", key)]
+                .iter()
+                .any(|k| {
+                    item.strip_prefix(k.as_str())
+                        .is_some_and(|r| r.starts_with(':'))
+                })
+        })?;
+        found = Some(idx + 1);
+    }
+    found
+}
+
+/// Upgrades services.yaml text to `SERVICES_SCHEMA_VERSION`, keeping comments
+/// and layout; `None` if it is already current.
+pub fn migrate_services_yaml(content: &str) -> Result<Option<String>> {
+    let before: ServicesFile =
+        serde_yaml::from_str(content).context("Failed to parse services file")?;
+    if before.version == Some(SERVICES_SCHEMA_VERSION) {
+        return Ok(None);
+    }
+
+    let mut out: Vec<String> = Vec::new();
+    let mut version_written = false;
+    let mut owners_indent: Option<usize> = None;
+    for line in content.lines() {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            out.push(line.to_string());
+            continue;
+        }
+        if indent == 0 && trimmed.starts_with("version:") {
+            out.push(format!("version: {}", SERVICES_SCHEMA_VERSION));
+            version_written = true;
+            continue;
+        }
+        if indent == 0 && !version_written && trimmed != "---" {
+            out.push(format!("version: {}", SERVICES_SCHEMA_VERSION));
+            version_written = true;
+        }
+        if let Some(block) = owners_indent {
+            let in_block = indent > block || (indent == block && trimmed.starts_with("- "));
+            if !in_block {
+                owners_indent = None;
+            } else if let Some(item) = trimmed.strip_prefix("- ") {
+                out.push(match typed_owner_item(item) {
+                    Some(typed) => format!("{}- {}", &line[..indent], typed),
+                    None => line.to_string(),
+                });
+                continue;
+            }
+        }
+        if let Some(rest) = trimmed.strip_prefix("owners:") {
+            let rest = rest.trim();
+            if rest.is_empty() || rest.starts_with('#') {
+                owners_indent = Some(indent);
+            }
+        }
+        out.push(line.to_string());
+    }
+    let mut migrated = out.join("\n");
+    if content.ends_with('\n') {
+        migrated.push('\n');
+    }
+
+    let after: ServicesFile = serde_yaml::from_str(&migrated)
+        .context("Migration produced an invalid services file; please report this")?;
+    if services_fingerprint(&before) != services_fingerprint(&after) {
+        anyhow::bail!("Migration would change the services' data; please migrate by hand");
+    }
+    Ok(Some(migrated))
+}
+
+/// `team: "@org/x"` / `user: "@x"` / `email: "a@b"` for a plain owner list
+/// item (keeping a trailing comment), or `None` to leave the item alone.
+fn typed_owner_item(item: &str) -> Option<String> {
+    let raw = item.find(" #").map_or(item, |pos| &item[..pos]).trim_end();
+    let (value, comment) = (raw.trim(), &item[raw.len()..]);
+    let unquoted = ['"', '\'']
+        .iter()
+        .find_map(|&q| value.strip_prefix(q)?.strip_suffix(q))
+        .unwrap_or(value);
+    if unquoted.contains([':', '"', '\'', ' ']) {
+        return None;
+    }
+    let key = match unquoted.strip_prefix('@') {
+        Some(rest) if rest.contains('/') => "team",
+        Some(_) => "user",
+        None if unquoted.contains('@') => "email",
+        None => return None,
+    };
+    Some(format!("{}: \"{}\"{}", key, unquoted, comment))
+}
+
+/// Services with owners reduced to their display strings, for checking that
+/// a migration kept the data intact.
+fn services_fingerprint(file: &ServicesFile) -> HashMap<String, serde_yaml::Value> {
+    file.services
+        .iter()
+        .map(|(name, def)| {
+            let mut def = def.clone();
+            if let Some(owners) = &mut def.owners {
+                for owner in owners.iter_mut() {
+                    *owner = Owner::Raw(owner.to_string());
+                }
+            }
+            let value = serde_yaml::to_value(&def).unwrap_or(serde_yaml::Value::Null);
+            (name.clone(), value)
+        })
+        .collect()
+}
+
+/// Expands `${VAR}` in every string value under `value`, leaving mapping
+/// keys alone. `at` is the dotted path so far, for error messages.
+fn expand_env_values(value: &mut serde_yaml::Value, at: &mut String) -> Result<()> {
+    match value {
+        serde_yaml::Value::String(s) => {
+            *s = expand_env_str(s).with_context(|| format!("in '{}'", at))?;
+        }
+        serde_yaml::Value::Sequence(items) => {
+            for (idx, item) in items.iter_mut().enumerate() {
+                let len = at.len();
+                at.push_str(&format!("[{}]", idx));
+                expand_env_values(item, at)?;
+                at.truncate(len);
+            }
+        }
+        serde_yaml::Value::Mapping(map) => {
+            for (key, item) in map.iter_mut() {
+                let len = at.len();
+                if !at.is_empty() {
+                    at.push('.');
+                }
+                match key.as_str() {
+                    Some(k) => at.push_str(k),
+                    None => at.push('?'),
+                }
+                expand_env_values(item, at)?;
+                at.truncate(len);
+            }
+        }
+        serde_yaml::Value::Tagged(tagged) => expand_env_values(&mut tagged.value, at)?,
+        _ => {}
+    }
+    Ok(())
+}
+
+/// `${VAR}` from the environment; `$${` is an escaped literal `${`, and any
+/// other `$` is left as is.
+fn expand_env_str(s: &str) -> Result<String> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos..];
+        if let Some(after) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(body) = tail.strip_prefix("${") {
+            let end = body
+                .find('}')
+                .with_context(|| format!("unterminated '${{' in '{}'", s))?;
+            let name = &body[..end];
+            if name.is_empty() {
+                anyhow::bail!("empty variable name in '{}'", s);
+            }
+            let val = std::env::var(name)
+                .ok()
+                .with_context(|| format!("variable ${{{}}} is not set", name))?;
+            out.push_str(&val);
+            rest = &body[end + 1..];
+        } else {
+            out.push('$');
+            rest = &tail[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+impl fmt::Display for Owner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Owner::Team { team, .. } => write!(f, "{}", team),
+            Owner::User { user, .. } => write!(f, "{}", user),
+            Owner::Email { email } => write!(f, "{}", email),
+            Owner::Raw(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+impl Owner {
+    /// Renders the owner as a GitHub mention (`@org/team`, `@user`). Emails
+    /// can't be mentioned and are returned as-is.
+    pub fn mention(&self) -> String {
+        let at = |s: &str| {
+            if s.starts_with('@') {
+                s.to_string()
+            } else {
+                format!("@{}", s)
+            }
+        };
+        match self {
+            Owner::Team { team, .. } => at(team),
+            Owner::User { user, .. } => at(user),
+            Owner::Email { email } => email.clone(),
+            Owner::Raw(s) if s.contains('@') && !s.starts_with('@') => s.clone(),
+            Owner::Raw(s) => at(s),
+        }
+    }
+
+    /// Renders the owner in `format`; Slack falls back to the plain name without a `slack` id.
+    pub fn render(&self, format: OwnerFormat) -> String {
+        match format {
+            OwnerFormat::Mention => self.mention(),
+            OwnerFormat::Plain => {
+                let s = self.to_string();
+                match s.strip_prefix('@') {
+                    Some(rest) => rest.to_string(),
+                    None => s,
+                }
+            }
+            OwnerFormat::Slack => match self {
+                Owner::Team {
+                    slack: Some(id), ..
+                } => format!("<!subteam^{}>", id),
+                Owner::User {
+                    slack: Some(id), ..
+                } => format!("<@{}>", id),
+                _ => self.render(OwnerFormat::Plain),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, JsonSchema)]
 pub struct Contact {
     pub slack: Option<String>,
     pub email: Option<String>,
+    /// PagerDuty service id for paging the on-call
+    pub pagerduty: Option<String>,
+    /// Escalation / on-call schedule URL
+    pub oncall_url: Option<String>,
+}
+
+impl Contact {
+    /// Labelled contact entries that are set, in display order.
+    pub fn entries(&self) -> Vec<(&'static str, &str)> {
+        let mut out = Vec::new();
+        if let Some(v) = &self.slack {
+            out.push(("slack", v.as_str()));
+        }
+        if let Some(v) = &self.email {
+            out.push(("email", v.as_str()));
+        }
+        if let Some(v) = &self.pagerduty {
+            out.push(("pagerduty", v.as_str()));
+        }
+        if let Some(v) = &self.oncall_url {
+            out.push(("oncall", v.as_str()));
+        }
+        out
+    }
 }
 
 /// A match explanation
@@ -40,184 +635,3189 @@ pub struct Contact {
 pub struct ExplainMatch<'a> {
     pub service: &'a str,
     pub pattern: String,
+    /// What `pattern` was normalized to (e.g. `config.yaml` -> `**/config.yaml`)
+    pub normalized: &'a str,
+    /// 1-based line of the rule in SERVICEOWNERS
+    pub line: usize,
+    /// `ServiceMapper::specificity` of the rule
+    pub specificity: usize,
+    /// File the rule was read from, if it came from a file
+    pub source: Option<&'a Path>,
+    /// `@priority:N` of the rule (0 if unset)
+    pub priority: i32,
+    /// Set when a `!pattern` line for this service matches the path, so the
+    /// rule is skipped
+    pub excluded_by: Option<&'a Exclusion>,
+    /// Whether this rule decides the path under the strategy explained with
+    pub chosen: bool,
+    /// Not a rule: no rule decided the path, so the mapper's default service
+    /// (`with_default_service`) does. `pattern` is empty and `line` is 0.
+    pub fallback: bool,
+}
+
+/// How a mapper picks among matching rules of equal `@priority`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum MatchStrategy {
+    /// The last matching rule in the file (CODEOWNERS semantics)
+    #[default]
+    #[cfg_attr(feature = "cli", value(name = "last"))]
+    LastWins,
+    /// The first matching rule in the file
+    #[cfg_attr(feature = "cli", value(name = "first"))]
+    FirstWins,
+    /// The rule with the most literal segments, then the fewest wildcards; ties
+    /// go to the later rule.
+    #[cfg_attr(feature = "cli", value(name = "specific"))]
+    MostSpecific,
+}
+
+/// A `!pattern service` line: no rule of `service` owns a path it matches,
+/// wherever the rule appears. Other services' rules are unaffected.
+#[derive(Debug, Clone)]
+pub struct Exclusion {
+    /// The pattern as written, without the `!`
+    pub pattern: String,
+    pub normalized: String,
+    pub service: String,
+    /// 1-based line in SERVICEOWNERS
+    pub line: usize,
+    source: Option<Arc<Path>>,
+    glob: globset::Glob,
+}
+
+impl Exclusion {
+    /// File the exclusion was read from; `None` if it was parsed from a string.
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+}
+
+/// A rule that doesn't match a path but spells out some of its leading directories
+#[derive(Debug)]
+pub struct NearMiss<'a> {
+    pub service: &'a str,
+    pub pattern: &'a str,
+    /// 1-based line of the rule in SERVICEOWNERS
+    pub line: usize,
+    /// File the rule was read from, if it came from a file
+    pub source: Option<&'a Path>,
+    /// Leading path segments the rule's glob shares literally with the path
+    pub shared_segments: usize,
+}
+
+/// Two rules whose patterns normalize to the same glob
+#[derive(Debug)]
+pub struct PatternClash<'a> {
+    pub pattern: &'a str,
+    pub previous_line: usize,
+    pub previous_service: &'a str,
+    pub line: usize,
+    pub service: &'a str,
+}
+
+impl PatternClash<'_> {
+    /// True when the rules disagree on the service (as opposed to an exact duplicate)
+    pub fn is_conflict(&self) -> bool {
+        self.previous_service != self.service
+    }
+}
+
+/// Options controlling how SERVICEOWNERS rules are compiled
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Let `*` cross `/` like CODEOWNERS does, so `docs/*` also matches
+    /// `docs/a/b.md`. Off by default: strict separators keep rules precise.
+    pub codeowners_compat: bool,
+    /// Only paths under this directory (e.g. `services/`) can match; anything
+    /// else is unmapped without consulting the rules.
+    pub root_scope: Option<String>,
+    /// Delimiter between pattern and service (e.g. `|`); `None` splits on
+    /// the first run of whitespace. Both fields are trimmed either way.
+    pub separator: Option<String>,
 }
 
 /// Core mapper that resolves paths to services
+#[derive(Debug, Clone)]
 pub struct ServiceMapper {
-    glob_set: GlobSet,
+    index: RuleIndex,
     /// Maps glob index to service name
     service_names: Vec<String>,
     /// Maps glob index to the original pattern (for explanation)
     pub patterns: Vec<String>,
+    /// Maps glob index to the normalized glob it was compiled from
+    normalized: Vec<String>,
+    /// Maps glob index to its compiled glob, kept so merges don't recompile
+    globs: Vec<globset::Glob>,
+    /// Maps glob index to its 1-based line number in the source file
+    pub lines: Vec<usize>,
+    /// Maps glob index to the file it was read from (`None` for parsed strings)
+    sources: Vec<Option<Arc<Path>>>,
+    /// Maps glob index to the inline `@owner` tokens that follow the service name
+    owners: Vec<Vec<Owner>>,
+    /// Maps glob index to its `@priority:N` (0 if absent); higher wins before file order
+    priorities: Vec<i32>,
+    /// `!pattern service` lines, in file order
+    exclusions: Vec<Exclusion>,
+    /// One glob per exclusion, parallel to `exclusions`
+    exclusion_set: GlobSet,
+    /// Directory prefix (with trailing `/`) that paths must be under to match
+    root_scope: Option<String>,
+    /// How `find_service` and friends pick among matching rules
+    strategy: MatchStrategy,
+    /// Service for paths no rule decides, instead of leaving them unmapped
+    default_service: Option<String>,
 }
 
 impl ServiceMapper {
     pub fn from_file(path: &Path) -> Result<Self> {
+        Self::from_file_with(path, &ParseOptions::default())
+    }
+
+    pub fn from_file_with(path: &Path, opts: &ParseOptions) -> Result<Self> {
         let content = fs::read_to_string(path)
             .with_context(|| format!("Failed to read SERVICEOWNERS file at {:?}", path))?;
-        Self::parse(&content)
+        let mut mapper = Self::parse_with(&content, opts)?;
+        let source: Arc<Path> = Arc::from(path);
+        for exclusion in &mut mapper.exclusions {
+            exclusion.source = Some(source.clone());
+        }
+        mapper.sources.fill(Some(source));
+        Ok(mapper)
     }
 
     pub fn parse(content: &str) -> Result<Self> {
-        let mut builder = GlobSetBuilder::new();
+        Self::parse_with(content, &ParseOptions::default())
+    }
+
+    pub fn parse_with(content: &str, opts: &ParseOptions) -> Result<Self> {
         let mut service_names = Vec::new();
         let mut patterns = Vec::new();
+        let mut normalized = Vec::new();
+        let mut globs = Vec::new();
+        let mut lines = Vec::new();
+        let mut owners = Vec::new();
+        let mut priorities = Vec::new();
+        let mut exclusions = Vec::new();
+        let separator = opts.separator.as_deref().filter(|s| !s.is_empty());
 
-        for (line_idx, line) in content.lines().enumerate() {
-            let line = line.trim();
-            if line.is_empty() || line.starts_with('#') {
+        for (line_no, line) in rule_lines(content) {
+            let (negated, rule) = parse_line(line, line_no, separator, opts)
+                .map_err(|diag| anyhow::anyhow!(diag.message))?;
+            if negated {
+                exclusions.push(Exclusion {
+                    pattern: rule.pattern.to_string(),
+                    normalized: rule.normalized,
+                    service: rule.service.to_string(),
+                    line: line_no,
+                    source: None,
+                    glob: rule.glob,
+                });
                 continue;
             }
-
-            // Format: "pattern    service"
-            let split_once: Vec<&str> = line.splitn(2, |c: char| c.is_whitespace()).collect();
-            if split_once.len() < 2 {
-                anyhow::bail!(
-                    "Invalid line {}: '{}' - expected 'pattern service'",
-                    line_idx + 1,
-                    line
-                );
-            }
-            let raw_pattern = split_once[0];
-            let service = split_once[1].trim();
-
-            let glob_str = normalize_pattern(raw_pattern)?;
-            let glob = GlobBuilder::new(&glob_str)
-                .literal_separator(true) // match / as separator
-                .build()
-                .with_context(|| {
-                    format!(
-                        "Invalid glob pattern on line {}: {}",
-                        line_idx + 1,
-                        raw_pattern
-                    )
-                })?;
-
-            builder.add(glob);
-            service_names.push(service.to_string());
-            patterns.push(raw_pattern.to_string());
+            globs.push(rule.glob);
+            service_names.push(rule.service.to_string());
+            patterns.push(rule.pattern.to_string());
+            normalized.push(rule.normalized);
+            lines.push(line_no);
+            priorities.push(rule.priority);
+            owners.push(
+                rule.owners
+                    .into_iter()
+                    .map(|o| Owner::Raw(o.to_string()))
+                    .collect(),
+            );
         }
 
-        let glob_set = builder.build().context("Failed to build glob set")?;
+        let index = RuleIndex::build(&globs, &normalized)?;
+        let exclusion_set = build_exclusion_set(&exclusions)?;
+        log::info!(
+            "Parsed {} SERVICEOWNERS rules, {} exclusions",
+            patterns.len(),
+            exclusions.len()
+        );
         Ok(Self {
-            glob_set,
+            index,
+            exclusions,
+            exclusion_set,
             service_names,
             patterns,
+            normalized,
+            globs,
+            sources: vec![None; lines.len()],
+            lines,
+            owners,
+            priorities,
+            root_scope: opts.root_scope.as_deref().and_then(normalize_scope),
+            strategy: MatchStrategy::default(),
+            default_service: None,
         })
     }
 
-    pub fn find_service(&self, path: &str) -> Option<&str> {
-        let matches = self.glob_set.matches(path);
-        matches
-            .iter()
-            .max()
-            .map(|idx| self.service_names[*idx].as_str())
+    /// The same rules, with `service` owning every in-scope path no rule decides.
+    pub fn with_default_service(mut self, service: impl Into<String>) -> Self {
+        self.default_service = Some(service.into());
+        self
     }
 
-    pub fn explain_service(&self, path: &str) -> Vec<ExplainMatch<'_>> {
-        let matches = self.glob_set.matches(path);
-        let mut result = Vec::new();
-        for idx in matches {
-            result.push(ExplainMatch {
-                service: &self.service_names[idx],
-                pattern: self.patterns[idx].clone(),
-            });
-        }
-        result
+    /// The service set with `with_default_service`, if any.
+    pub fn default_service(&self) -> Option<&str> {
+        self.default_service.as_deref()
     }
-}
 
-pub fn normalize_pattern(pat: &str) -> Result<String> {
-    // 1. strip
-    let mut s = pat.trim().to_string();
-    if s.is_empty() {
-        return Ok(s);
+    /// The same rules resolved with `strategy` by every query that doesn't
+    /// take one (`find_service`, `winning_rule`, `explain_service`, ...).
+    pub fn with_match_strategy(mut self, strategy: MatchStrategy) -> Self {
+        self.strategy = strategy;
+        self
     }
 
-    // 2. replace backslash
-    s = s.replace('\\', "/");
+    /// The strategy used by queries that don't take one; `LastWins` unless
+    /// set with `with_match_strategy`.
+    pub fn match_strategy(&self) -> MatchStrategy {
+        self.strategy
+    }
 
-    // 3. strip leading ./
-    while s.starts_with("./") {
-        s = s[2..].to_string();
+    /// Concatenates the rules of several mappers, in order. Scope and match
+    /// strategy must agree, and so must any default services.
+    pub fn from_mappers<I>(mappers: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = ServiceMapper>,
+    {
+        let mut merged: Option<Self> = None;
+        for mapper in mappers {
+            match &mut merged {
+                None => merged = Some(mapper),
+                Some(acc) => {
+                    if acc.root_scope != mapper.root_scope {
+                        anyhow::bail!(
+                            "Cannot merge SERVICEOWNERS rules with different scopes ({:?} and {:?})",
+                            acc.root_scope,
+                            mapper.root_scope
+                        );
+                    }
+                    if acc.strategy != mapper.strategy {
+                        anyhow::bail!(
+                            "Cannot merge SERVICEOWNERS rules with different match strategies ({:?} and {:?})",
+                            acc.strategy,
+                            mapper.strategy
+                        );
+                    }
+                    match (&acc.default_service, mapper.default_service) {
+                        (Some(a), Some(b)) if *a != b => anyhow::bail!(
+                            "Cannot merge SERVICEOWNERS rules with different default services ('{}' and '{}')",
+                            a,
+                            b
+                        ),
+                        (None, b) => acc.default_service = b,
+                        _ => {}
+                    }
+                    acc.service_names.extend(mapper.service_names);
+                    acc.patterns.extend(mapper.patterns);
+                    acc.normalized.extend(mapper.normalized);
+                    acc.globs.extend(mapper.globs);
+                    acc.lines.extend(mapper.lines);
+                    acc.sources.extend(mapper.sources);
+                    acc.owners.extend(mapper.owners);
+                    acc.priorities.extend(mapper.priorities);
+                    acc.exclusions.extend(mapper.exclusions);
+                }
+            }
+        }
+        let mut merged = match merged {
+            Some(merged) => merged,
+            None => Self::parse("")?,
+        };
+        merged.index = RuleIndex::build(&merged.globs, &merged.normalized)?;
+        merged.exclusion_set = build_exclusion_set(&merged.exclusions)?;
+        log::info!("Merged into {} SERVICEOWNERS rules", merged.len());
+        Ok(merged)
     }
 
-    // 4. strip leading / (anchor to root is implied by 'contains slash' logic + absolute match)
-    if s.starts_with('/') {
-        s = s[1..].to_string();
+    /// `other`'s rules appended after this mapper's; see `from_mappers`.
+    pub fn merge(self, other: ServiceMapper) -> Result<Self> {
+        Self::from_mappers([self, other])
     }
 
-    // 5. trailing slash => /**
-    if s.ends_with('/') {
-        if s == "/" {
-            return Ok("**".to_string());
+    /// Every diagnostic for a SERVICEOWNERS source: syntax errors, then `validate`.
+    pub fn validate_source(content: &str, opts: &ParseOptions) -> Vec<Diagnostic> {
+        let separator = opts.separator.as_deref().filter(|s| !s.is_empty());
+        let syntax: Vec<Diagnostic> = rule_lines(content)
+            .filter_map(|(line_no, line)| parse_line(line, line_no, separator, opts).err())
+            .collect();
+        if !syntax.is_empty() {
+            return syntax;
+        }
+        match Self::parse_with(content, opts) {
+            Ok(mapper) => mapper.validate(),
+            Err(err) => vec![Diagnostic {
+                line: None,
+                severity: Severity::Error,
+                code: "invalid-file",
+                pattern: None,
+                message: format!("{:#}", err),
+            }],
         }
-        s.pop(); // remove /
-        s.push_str("/**");
     }
 
-    // 6. If no slash, prepend **/
-    if !s.contains('/') {
-        s = format!("**/{}", s);
+    /// Rule-level warnings for a parsed file (empty, duplicate, conflicting,
+    /// shadowed rules and unused exclusions).
+    pub fn validate(&self) -> Vec<Diagnostic> {
+        let mut diags = Vec::new();
+        if self.is_empty() {
+            diags.push(Diagnostic {
+                line: None,
+                severity: Severity::Warning,
+                code: "no-rules",
+                pattern: None,
+                message: "SERVICEOWNERS contains no rules; every file will be unmapped."
+                    .to_string(),
+            });
+        }
+        for clash in self.pattern_clashes() {
+            let (code, message) = if clash.is_conflict() {
+                (
+                    "conflict",
+                    format!(
+                        "Pattern '{}' on line {} maps to '{}', but line {} maps it to '{}'.",
+                        clash.pattern,
+                        clash.line,
+                        clash.service,
+                        clash.previous_line,
+                        clash.previous_service
+                    ),
+                )
+            } else {
+                (
+                    "duplicate",
+                    format!(
+                        "Pattern '{}' on line {} duplicates line {}.",
+                        clash.pattern, clash.line, clash.previous_line
+                    ),
+                )
+            };
+            diags.push(Diagnostic {
+                line: Some(clash.line),
+                severity: Severity::Warning,
+                code,
+                pattern: Some(clash.pattern.to_string()),
+                message,
+            });
+        }
+        for (idx, by) in self.shadowed_rules() {
+            diags.push(Diagnostic {
+                line: Some(self.lines[idx]),
+                severity: Severity::Warning,
+                code: "shadowed",
+                pattern: Some(self.patterns[idx].clone()),
+                message: format!(
+                    "Pattern '{}' on line {} is shadowed by '{}' on line {} and never wins.",
+                    self.patterns[idx], self.lines[idx], self.patterns[by], self.lines[by]
+                ),
+            });
+        }
+        for exclusion in &self.exclusions {
+            if !self.service_names.contains(&exclusion.service) {
+                diags.push(Diagnostic {
+                    line: Some(exclusion.line),
+                    severity: Severity::Warning,
+                    code: "unused-exclusion",
+                    pattern: Some(format!("!{}", exclusion.pattern)),
+                    message: format!(
+                        "Exclusion '!{}' on line {} is for '{}', which no rule maps to.",
+                        exclusion.pattern, exclusion.line, exclusion.service
+                    ),
+                });
+            }
+        }
+        diags.sort_by_key(|d| d.line);
+        diags
     }
 
-    Ok(s)
-}
+    /// `(rule, later rule)` pairs where the later rule provably matches every path
+    /// the earlier one does.
+    pub fn shadowed_rules(&self) -> Vec<(usize, usize)> {
+        let mut shadowed = Vec::new();
+        for (idx, glob) in self.normalized.iter().enumerate() {
+            let by = (idx + 1..self.normalized.len()).rev().find(|&later| {
+                let broader = &self.normalized[later];
+                if broader == glob || self.priorities[later] < self.priorities[idx] {
+                    return false;
+                }
+                if self
+                    .exclusions
+                    .iter()
+                    .any(|e| e.service == self.service_names[later])
+                {
+                    return false;
+                }
+                if broader == "**" {
+                    return true;
+                }
+                match broader.strip_suffix("/**") {
+                    Some(dir) if !dir.contains(['*', '?', '[', '{', '\\']) => {
+                        glob.starts_with(dir) && glob[dir.len()..].starts_with('/')
+                    }
+                    _ => false,
+                }
+            });
+            if let Some(by) = by {
+                shadowed.push((idx, by));
+            }
+        }
+        shadowed
+    }
 
-/// Heuristics for Init command
-pub fn init_from_codeowners(codeowners_path: &Path) -> Result<String> {
-    let content = fs::read_to_string(codeowners_path)
-        .with_context(|| format!("Failed to read CODEOWNERS at {:?}", codeowners_path))?;
+    /// Number of rules.
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
 
-    let mut out = String::new();
-    out.push_str("# Generated from CODEOWNERS by serviceowners (init)\n");
-    out.push_str("# pattern            service\n");
+    /// True when the file had no rules (e.g. only comments), so nothing maps.
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
 
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') {
-            continue;
-        }
-        let parts: Vec<&str> = line.split_whitespace().collect();
-        if parts.len() < 2 {
-            continue;
-        }
+    /// The directory prefix paths must be under to match, if scoped.
+    pub fn root_scope(&self) -> Option<&str> {
+        self.root_scope.as_deref()
+    }
 
-        let pattern = parts[0];
-        let owners = &parts[1..];
+    fn in_scope(&self, path: &str) -> bool {
+        match &self.root_scope {
+            Some(scope) => path.starts_with(scope.as_str()),
+            None => true,
+        }
+    }
 
-        let service = infer_service_name(pattern, owners);
-        out.push_str(&format!("{:<20} {}\n", pattern, service));
+    /// Normalized glob that rule `idx` was compiled from.
+    pub fn normalized_pattern(&self, idx: usize) -> &str {
+        &self.normalized[idx]
     }
-    Ok(out)
-}
 
-fn infer_service_name(pattern: &str, owners: &[&str]) -> String {
-    let p = pattern.trim_start_matches('/').trim_end_matches('/');
-    let segments: Vec<&str> = p.split('/').collect();
+    /// How narrowly rule `idx` targets paths: the number of path segments in
+    /// its glob that contain no wildcard (`apps/api/**` is 2, `**/*.md` is 0).
+    pub fn specificity(&self, idx: usize) -> usize {
+        self.normalized[idx]
+            .split('/')
+            .filter(|seg| !seg.is_empty() && !seg.contains(['*', '?', '[', '{']))
+            .count()
+    }
 
-    let candidates: Vec<&str> = segments
-        .iter()
-        .filter(|&&s| {
-            s != "*" && s != "**" && s != "src" && s != "lib" && s != "packages" && s != "apps"
-        })
-        .cloned()
-        .collect();
+    /// File rule `idx` was read from; `None` if it was parsed from a string.
+    pub fn rule_source(&self, idx: usize) -> Option<&Path> {
+        self.sources[idx].as_deref()
+    }
 
-    if let Some(last) = candidates.last() {
-        let name = last.replace(|c: char| !c.is_alphanumeric(), "_");
-        if !name.is_empty() {
-            return name.to_lowercase();
+    /// Distinct files that contributed rules, in rule order.
+    pub fn sources(&self) -> Vec<&Path> {
+        let mut seen: Vec<&Path> = Vec::new();
+        for source in self.sources.iter().flatten() {
+            if !seen.contains(&source.as_ref()) {
+                seen.push(source);
+            }
         }
+        seen
     }
 
-    if let Some(owner) = owners.first() {
-        let o = owner.trim_start_matches('@');
-        if let Some((_, name)) = o.split_once('/') {
-            return name.replace('-', "_").to_lowercase();
-        }
-        return o.replace('-', "_").to_lowercase();
+    /// Inline owners declared on rule `idx`.
+    pub fn rule_owners(&self, idx: usize) -> &[Owner] {
+        &self.owners[idx]
     }
 
-    "unknown_service".to_string()
+    /// Inline owners of the rule that wins for `path`. `None` if the path is
+    /// unmapped or the winning rule declares no owners.
+    pub fn owners_for_path(&self, path: &str) -> Option<&[Owner]> {
+        let owners = &self.owners[self.winning_rule(path)?];
+        (!owners.is_empty()).then_some(owners.as_slice())
+    }
+
+    /// Services built from inline owners alone, for repos without services.yaml.
+    /// A service's owners are the union over its rules, in file order.
+    pub fn inline_services(&self) -> ServicesFile {
+        let mut services: BTreeMap<String, ServiceDef> = BTreeMap::new();
+        for (svc, rule_owners) in self.service_names.iter().zip(&self.owners) {
+            if rule_owners.is_empty() {
+                continue;
+            }
+            let owners = services
+                .entry(svc.clone())
+                .or_default()
+                .owners
+                .get_or_insert_with(Vec::new);
+            for owner in rule_owners {
+                if !owners.iter().any(|o| o.to_string() == owner.to_string()) {
+                    owners.push(owner.clone());
+                }
+            }
+        }
+        ServicesFile {
+            version: None,
+            services,
+        }
+    }
+
+    pub fn find_service(&self, path: &str) -> Option<&str> {
+        self.find_service_with(path, self.strategy)
+    }
+
+    /// Groups `files` by owning service, as `sowners impacted` does.
+    pub fn impacted(&self, files: &[String]) -> ImpactReport {
+        let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut unmapped_files = Vec::new();
+        for file in files {
+            match self.find_service(file) {
+                Some(svc) => grouped
+                    .entry(svc.to_string())
+                    .or_default()
+                    .push(file.clone()),
+                None => unmapped_files.push(file.clone()),
+            }
+        }
+        ImpactReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            impacted_services: grouped.keys().cloned().collect(),
+            services: grouped
+                .into_iter()
+                .map(|(svc, files)| (svc, ServiceImpact::new(files, None)))
+                .collect(),
+            unmapped_files,
+            ignored_files: Vec::new(),
+            ownerless_services: None,
+            moved_within_service: None,
+            moved_across_services: None,
+            unmapped_groups: None,
+            file_statuses: None,
+            teams: None,
+            no_team: None,
+        }
+    }
+
+    /// Service for `path` with the rule picked by `strategy` instead of the
+    /// last match.
+    pub fn find_service_with(&self, path: &str, strategy: MatchStrategy) -> Option<&str> {
+        let winner = self.winning_rule_with(path, strategy);
+        if log::log_enabled!(log::Level::Debug) {
+            self.log_decision(path, winner, strategy);
+        }
+        match winner {
+            Some(idx) => Some(self.service_names[idx].as_str()),
+            None => self.fallback_service(path),
+        }
+    }
+
+    /// The default service for an in-scope `path` no rule decides.
+    fn fallback_service(&self, path: &str) -> Option<&str> {
+        let service = self.default_service.as_deref()?;
+        self.in_scope(&normalize_query_path(path))
+            .then_some(service)
+    }
+
+    fn log_decision(&self, path: &str, winner: Option<usize>, strategy: MatchStrategy) {
+        let Some(idx) = winner else {
+            if self.in_scope(path) {
+                log::debug!("{}: no rule matched", path);
+            } else {
+                log::debug!("{}: outside scope {:?}", path, self.root_scope);
+            }
+            return;
+        };
+        let mut matched = Vec::new();
+        self.matching_rules_into(path, &mut matched);
+        matched.sort_unstable();
+        let lines: Vec<String> = matched.iter().map(|&i| self.lines[i].to_string()).collect();
+        let reason = match (self.priorities[idx], strategy) {
+            (0, MatchStrategy::LastWins) => "as the last match".to_string(),
+            (0, MatchStrategy::FirstWins) => "as the first match".to_string(),
+            (0, MatchStrategy::MostSpecific) => {
+                format!("as the most specific (score {})", self.specificity(idx))
+            }
+            (p, _) => format!("with priority {}", p),
+        };
+        log::debug!(
+            "{}: matched lines [{}]; line {} ({} -> {}) wins {}",
+            path,
+            lines.join(", "),
+            self.lines[idx],
+            self.patterns[idx],
+            self.service_names[idx],
+            reason
+        );
+    }
+
+    /// Service for each rule, parallel to `patterns`
+    pub fn service_names(&self) -> &[String] {
+        &self.service_names
+    }
+
+    /// Index of the rule that decides `path`, skipping excluded rules
+    pub fn winning_rule(&self, path: &str) -> Option<usize> {
+        self.winning_rule_with(path, self.strategy)
+    }
+
+    /// `winning_rule` with ties in priority broken by `strategy`
+    pub fn winning_rule_with(&self, path: &str, strategy: MatchStrategy) -> Option<usize> {
+        let path = normalize_query_path(path);
+        if !self.in_scope(&path) {
+            return None;
+        }
+        let exclusions = self.matching_exclusions(&path);
+        self.index
+            .matches(path.as_ref())
+            .into_iter()
+            .filter(|&idx| self.excluded_by(idx, &exclusions).is_none())
+            .max_by_key(|&idx| self.precedence(idx, strategy))
+    }
+
+    /// Sort key for `strategy`: the greatest key wins.
+    fn precedence(&self, idx: usize, strategy: MatchStrategy) -> (i32, usize, isize, isize) {
+        let order = idx as isize;
+        match strategy {
+            MatchStrategy::LastWins => (self.priorities[idx], 0, 0, order),
+            MatchStrategy::FirstWins => (self.priorities[idx], 0, 0, -order),
+            MatchStrategy::MostSpecific => {
+                let wildcards = self.normalized[idx]
+                    .split('/')
+                    .filter(|seg| seg.contains(['*', '?', '[', '{']))
+                    .count();
+                (
+                    self.priorities[idx],
+                    self.specificity(idx),
+                    -(wildcards as isize),
+                    order,
+                )
+            }
+        }
+    }
+
+    /// `!pattern` lines, in file order
+    pub fn exclusions(&self) -> &[Exclusion] {
+        &self.exclusions
+    }
+
+    /// Indices into `exclusions` matching an already normalized `path` (or,
+    /// for a directory query, the directory itself)
+    fn matching_exclusions(&self, path: &str) -> Vec<usize> {
+        if self.exclusions.is_empty() {
+            return Vec::new();
+        }
+        let mut matching = self.exclusion_set.matches(path);
+        if let Some(dir) = directory_itself(path) {
+            matching.extend(self.exclusion_set.matches(dir));
+            matching.sort_unstable();
+            matching.dedup();
+        }
+        matching
+    }
+
+    /// The first of `matching` (from `matching_exclusions`) that excludes
+    /// rule `idx`'s service
+    fn excluded_by(&self, idx: usize, matching: &[usize]) -> Option<&Exclusion> {
+        matching
+            .iter()
+            .map(|&e| &self.exclusions[e])
+            .find(|e| e.service == self.service_names[idx])
+    }
+
+    /// `@priority:N` of rule `idx`; 0 when the rule doesn't set one.
+    pub fn priority(&self, idx: usize) -> i32 {
+        self.priorities[idx]
+    }
+
+    /// Suggests an owner for an unmapped path from its nearest ancestor directory.
+    pub fn nearest_service(&self, path: &str) -> Option<ExplainMatch<'_>> {
+        let path = normalize_query_path(path);
+        let path = path.trim_end_matches('/');
+        let (mut dir, name) = match path.rsplit_once('/') {
+            Some((dir, name)) => (dir, name),
+            None => return None,
+        };
+        loop {
+            let sibling = if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}/{}", dir, name)
+            };
+            let candidates = [dir, sibling.as_str()];
+            for candidate in candidates.iter().filter(|c| !c.is_empty()) {
+                if let Some(idx) = self.winning_rule(candidate) {
+                    return Some(self.explain_rule(idx, None, true));
+                }
+            }
+            if dir.is_empty() {
+                return None;
+            }
+            dir = dir.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("");
+        }
+    }
+
+    /// Up to `limit` rules not matching `path` that share its longest literal
+    /// directory prefix, best first.
+    pub fn near_misses(&self, path: &str, limit: usize) -> Vec<NearMiss<'_>> {
+        let path = normalize_query_path(path);
+        let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+        let matching = self.index.matches(path.as_ref());
+        let mut misses: Vec<(usize, usize)> = self
+            .normalized
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !matching.contains(idx))
+            .filter_map(|(idx, glob)| {
+                let shared = glob
+                    .split('/')
+                    .zip(&segments)
+                    .take_while(|(g, p)| !g.contains(['*', '?', '[', '{']) && g == *p)
+                    .count();
+                (shared > 0).then_some((idx, shared))
+            })
+            .collect();
+        misses.sort_by(|a, b| b.1.cmp(&a.1).then(b.0.cmp(&a.0)));
+        misses
+            .into_iter()
+            .take(limit)
+            .map(|(idx, shared)| NearMiss {
+                service: &self.service_names[idx],
+                pattern: &self.patterns[idx],
+                line: self.lines[idx],
+                source: self.rule_source(idx),
+                shared_segments: shared,
+            })
+            .collect()
+    }
+
+    /// Indices of every rule matching `path` and not excluded from it, into `out`.
+    pub fn matching_rules_into(&self, path: &str, out: &mut Vec<usize>) {
+        let path = normalize_query_path(path);
+        if !self.in_scope(&path) {
+            out.clear();
+            return;
+        }
+        self.index.matches_into(path.as_ref(), out);
+        let exclusions = self.matching_exclusions(&path);
+        if !exclusions.is_empty() {
+            out.retain(|&idx| self.excluded_by(idx, &exclusions).is_none());
+        }
+    }
+
+    /// Like `matching_rules_into`, but before exclusions are applied: every
+    /// in-scope rule whose glob matches `path`.
+    pub fn glob_matches_into(&self, path: &str, out: &mut Vec<usize>) {
+        let path = normalize_query_path(path);
+        if !self.in_scope(&path) {
+            out.clear();
+            return;
+        }
+        self.index.matches_into(path.as_ref(), out);
+    }
+
+    pub fn explain_service(&self, path: &str) -> Vec<ExplainMatch<'_>> {
+        self.explain_service_with(path, self.strategy)
+    }
+
+    /// Every rule matching `path`, in file order, with `chosen` set on the one
+    /// `strategy` picks.
+    pub fn explain_service_with(
+        &self,
+        path: &str,
+        strategy: MatchStrategy,
+    ) -> Vec<ExplainMatch<'_>> {
+        let winner = self.winning_rule_with(path, strategy);
+        let path = normalize_query_path(path);
+        if !self.in_scope(&path) {
+            return Vec::new();
+        }
+        let matches = self.index.matches(path.as_ref());
+        let exclusions = self.matching_exclusions(&path);
+        let mut result: Vec<ExplainMatch<'_>> = matches
+            .into_iter()
+            .map(|idx| {
+                let excluded_by = self.excluded_by(idx, &exclusions);
+                self.explain_rule(idx, excluded_by, winner == Some(idx))
+            })
+            .collect();
+        if winner.is_none() {
+            result.extend(self.fallback_match());
+        }
+        result
+    }
+
+    /// The non-excluded rules matching `path`, best first; the default service's
+    /// fallback when none match.
+    pub fn ranked_matches(&self, path: &str) -> Vec<ExplainMatch<'_>> {
+        let mut rules = Vec::new();
+        self.matching_rules_into(path, &mut rules);
+        rules.sort_by_key(|&idx| std::cmp::Reverse(self.precedence(idx, self.strategy)));
+        if rules.is_empty() && self.fallback_service(path).is_some() {
+            return self.fallback_match().into_iter().collect();
+        }
+        rules
+            .iter()
+            .enumerate()
+            .map(|(rank, &idx)| self.explain_rule(idx, None, rank == 0))
+            .collect()
+    }
+
+    fn explain_rule<'a>(
+        &'a self,
+        idx: usize,
+        excluded_by: Option<&'a Exclusion>,
+        chosen: bool,
+    ) -> ExplainMatch<'a> {
+        ExplainMatch {
+            service: &self.service_names[idx],
+            pattern: self.patterns[idx].clone(),
+            normalized: &self.normalized[idx],
+            line: self.lines[idx],
+            specificity: self.specificity(idx),
+            source: self.rule_source(idx),
+            priority: self.priorities[idx],
+            excluded_by,
+            chosen,
+            fallback: false,
+        }
+    }
+
+    /// The `fallback` entry for the default service, if one is set.
+    fn fallback_match(&self) -> Option<ExplainMatch<'_>> {
+        Some(ExplainMatch {
+            service: self.default_service.as_deref()?,
+            pattern: String::new(),
+            normalized: "",
+            line: 0,
+            specificity: 0,
+            source: None,
+            priority: 0,
+            excluded_by: None,
+            chosen: true,
+            fallback: true,
+        })
+    }
+
+    /// Rules that repeat an earlier rule's normalized pattern, each paired with the
+    /// rule it overrides.
+    pub fn pattern_clashes(&self) -> Vec<PatternClash<'_>> {
+        let mut last_seen: HashMap<&str, usize> = HashMap::new();
+        let mut clashes = Vec::new();
+        for (idx, glob) in self.normalized.iter().enumerate() {
+            if let Some(prev) = last_seen.insert(glob, idx) {
+                clashes.push(PatternClash {
+                    pattern: &self.patterns[idx],
+                    previous_line: self.lines[prev],
+                    previous_service: &self.service_names[prev],
+                    line: self.lines[idx],
+                    service: &self.service_names[idx],
+                });
+            }
+        }
+        clashes
+    }
+}
+
+/// Resolves paths end-to-end: SERVICEOWNERS for path → service, services.yaml
+/// for service → owners.
+#[derive(Debug, Clone)]
+pub struct OwnershipResolver {
+    pub mapper: ServiceMapper,
+    pub services: ServicesFile,
+}
+
+impl OwnershipResolver {
+    pub fn new(mapper: ServiceMapper, services: ServicesFile) -> Self {
+        Self { mapper, services }
+    }
+
+    pub fn from_files(serviceowners: &Path, services: &Path) -> Result<Self> {
+        Ok(Self::new(
+            ServiceMapper::from_file(serviceowners)?,
+            ServicesFile::load(services)?,
+        ))
+    }
+
+    /// Canonical service for a path (aliases in SERVICEOWNERS are resolved)
+    pub fn service_for_path(&self, path: &str) -> Option<&str> {
+        let service = self.mapper.find_service(path)?;
+        Some(self.services.canonical_name(service))
+    }
+
+    /// Metadata for a service or alias, or `None` if services.yaml doesn't declare it.
+    pub fn service_def(&self, service: &str) -> Option<&ServiceDef> {
+        self.services.get(service)
+    }
+
+    /// Owners for a path: services.yaml first, then the rule's inline `@owner`s.
+    pub fn owners_for_path(&self, path: &str) -> Option<&[Owner]> {
+        let service = self.service_for_path(path)?;
+        match self.service_def(service) {
+            Some(def) => def.owners.as_deref(),
+            None => self.mapper.owners_for_path(path),
+        }
+    }
+}
+
+/// Paths that are intentionally unowned, read from a `.sownersignore` file
+/// (gitignore syntax). Matches are reported as "ignored" rather than unmapped.
+#[cfg(feature = "ignore-file")]
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    matcher: ignore::gitignore::Gitignore,
+}
+
+#[cfg(feature = "ignore-file")]
+impl IgnoreRules {
+    /// An empty rule set: nothing is ignored.
+    pub fn empty() -> Self {
+        Self {
+            matcher: ignore::gitignore::Gitignore::empty(),
+        }
+    }
+
+    /// Loads rules from `path`. Patterns are relative to the repo root, like
+    /// SERVICEOWNERS. A missing file yields an empty rule set.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::empty());
+        }
+        let mut builder = ignore::gitignore::GitignoreBuilder::new("");
+        if let Some(err) = builder.add(path) {
+            return Err(err).with_context(|| format!("Failed to read {:?}", path));
+        }
+        let matcher = builder
+            .build()
+            .with_context(|| format!("Failed to parse {:?}", path))?;
+        Ok(Self { matcher })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matcher.is_empty()
+    }
+
+    /// Whether a repo-relative file path (or one of its parent directories)
+    /// is ignored. `!pattern` re-includes, as in `.gitignore`.
+    pub fn is_ignored(&self, path: &str) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(path, false)
+            .is_ignore()
+    }
+}
+
+/// Cleans up a query path (`./`, `//`, `.` segments), keeping one trailing `/`.
+pub fn normalize_query_path(path: &str) -> Cow<'_, str> {
+    let trimmed = path.trim_end_matches('/');
+    let trailing = path.len() - trimmed.len();
+    if trailing <= 1 && trimmed.split('/').all(|seg| !seg.is_empty() && seg != ".") {
+        return Cow::Borrowed(path);
+    }
+    let mut out = trimmed
+        .split('/')
+        .filter(|seg| !seg.is_empty() && *seg != ".")
+        .collect::<Vec<_>>()
+        .join("/");
+    if trailing > 0 && !out.is_empty() {
+        out.push('/');
+    }
+    Cow::Owned(out)
+}
+
+/// `src/auth` for a normalized directory query `src/auth/`.
+fn directory_itself(path: &str) -> Option<&str> {
+    path.strip_suffix('/').filter(|dir| !dir.is_empty())
+}
+
+/// `services`, `/services/` and `./services` all become `services/`. An
+/// empty scope (or `/`) means the whole repo.
+fn normalize_scope(scope: &str) -> Option<String> {
+    let scope = scope.trim().trim_start_matches("./").trim_matches('/');
+    (!scope.is_empty()).then(|| format!("{}/", scope))
+}
+
+// Lookups are shared across threads (e.g. a server answering ownership
+// queries); keep these types Send + Sync.
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ServiceMapper>();
+    assert_send_sync::<ServicesFile>();
+    assert_send_sync::<OwnershipResolver>();
+    #[cfg(feature = "ignore-file")]
+    assert_send_sync::<IgnoreRules>();
+};
+
+/// Splits `"service @a @b"` into the service name and its trailing owner
+/// tokens. Owners start at the first `@`-prefixed token.
+fn split_inline_owners(rest: &str) -> (&str, Vec<&str>) {
+    let mut offset = 0;
+    for token in rest.split_whitespace() {
+        let start = offset + rest[offset..].find(token).unwrap_or(0);
+        if token.starts_with('@') {
+            return (
+                rest[..start].trim(),
+                rest[start..].split_whitespace().collect(),
+            );
+        }
+        offset = start + token.len();
+    }
+    (rest, Vec::new())
+}
+
+/// How serious a `Diagnostic` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A problem found in SERVICEOWNERS or services.yaml; `DIAGNOSTIC_CODES` lists
+/// the codes.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct Diagnostic {
+    /// 1-based line, or `None` for file-level problems
+    pub line: Option<usize>,
+    pub severity: Severity,
+    pub code: &'static str,
+    /// The rule's pattern, when the problem is about one rule
+    pub pattern: Option<String>,
+    pub message: String,
+}
+
+/// Every `Diagnostic::code`, with what it reports. The last five come from
+/// `sowners lint` checks that need services.yaml or a walk of the repo.
+pub const DIAGNOSTIC_CODES: &[(&str, &str)] = &[
+    (
+        "invalid-line",
+        "a rule line without both a pattern and a service",
+    ),
+    (
+        "empty-pattern",
+        "a pattern that names nothing, such as `./`",
+    ),
+    (
+        "missing-service",
+        "inline owners without a service before them",
+    ),
+    (
+        "invalid-owner",
+        "an inline owner that doesn't start with `@`",
+    ),
+    (
+        "invalid-priority",
+        "a non-integer or repeated `@priority:N`",
+    ),
+    ("invalid-glob", "a pattern that doesn't compile"),
+    (
+        "invalid-exclusion",
+        "an exclusion with owners or `@priority`",
+    ),
+    ("invalid-file", "a file that doesn't load as a whole"),
+    ("invalid-yaml", "services.yaml that doesn't parse"),
+    (
+        "unknown-key",
+        "a services.yaml key that loading would ignore",
+    ),
+    ("no-rules", "a SERVICEOWNERS file without rules"),
+    ("duplicate", "a pattern repeated for the same service"),
+    ("conflict", "a pattern repeated for another service"),
+    ("shadowed", "a rule a later, broader rule always overrides"),
+    (
+        "unused-exclusion",
+        "an exclusion for a service no rule maps to",
+    ),
+    ("alias", "a rule that names a service by an alias"),
+    (
+        "undefined-service",
+        "a rule for a service services.yaml doesn't define",
+    ),
+    ("unused-service", "a declared service no rule maps to"),
+    ("link", "a malformed or unreachable docs/runbook link"),
+    ("unused-pattern", "a rule that matches no file in the repo"),
+];
+
+/// `schema_version` of the JSON reports (`ImpactReport`, `LintResult`, ...).
+pub const JSON_SCHEMA_VERSION: u32 = 1;
+
+/// One team's share of an impacted change, from `ImpactReport::by_team`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct TeamImpact {
+    /// Number of changed files across the team's services
+    pub count: usize,
+    /// The team's impacted services, sorted
+    pub services: Vec<String>,
+    pub files: Vec<String>,
+}
+
+impl TeamImpact {
+    fn add(&mut self, service: &str, files: &[String]) {
+        self.services.push(service.to_string());
+        self.files.extend(files.iter().cloned());
+        self.count = self.files.len();
+    }
+}
+
+/// Services and files touched by a change: the `impacted --format json` payload
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ImpactReport {
+    pub schema_version: u32,
+    /// Impacted services, sorted
+    pub impacted_services: Vec<String>,
+    /// Per-service detail, keyed by service name
+    pub services: BTreeMap<String, ServiceImpact>,
+    pub unmapped_files: Vec<String>,
+    /// Unmapped files listed in the ignore file
+    pub ignored_files: Vec<String>,
+    /// Impacted services without owners; present when owner metadata is checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ownerless_services: Option<Vec<String>>,
+    /// Renames that stayed within one service, when renames are detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moved_within_service: Option<Vec<MoveWithin>>,
+    /// Renames that changed ownership, when renames are detected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moved_across_services: Option<Vec<MoveAcross>>,
+    /// Unmapped file counts per top-level directory, when grouped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unmapped_groups: Option<Vec<UnmappedGroup>>,
+    /// How each listed file changed, when the files come from a git diff
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_statuses: Option<BTreeMap<String, FileStatus>>,
+    /// Impacted services grouped by owning team, with `--group-by team`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub teams: Option<BTreeMap<String, TeamImpact>>,
+    /// Impacted services without a team owner, with `--group-by team`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub no_team: Option<TeamImpact>,
+}
+
+impl ImpactReport {
+    /// The impacted services regrouped by `team:` owner.
+    pub fn by_team(&self, services: Option<&ServicesFile>) -> TeamBreakdown {
+        let mut breakdown = TeamBreakdown::default();
+        for (svc, detail) in &self.services {
+            let owners = services
+                .and_then(|s| s.get(svc))
+                .and_then(|def| def.owners.as_deref())
+                .unwrap_or_default();
+            let mut names: Vec<&str> = owners
+                .iter()
+                .filter_map(|owner| match owner {
+                    Owner::Team { team, .. } => Some(team.as_str()),
+                    _ => None,
+                })
+                .collect();
+            names.sort_unstable();
+            names.dedup();
+            if names.is_empty() {
+                let entry = breakdown.no_team.get_or_insert_with(TeamImpact::default);
+                entry.add(svc, &detail.files);
+            }
+            for name in names {
+                let entry = breakdown.teams.entry(name.to_string()).or_default();
+                entry.add(svc, &detail.files);
+            }
+        }
+        breakdown
+    }
+}
+
+/// Impacted services grouped by owning team, from `ImpactReport::by_team`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TeamBreakdown {
+    /// Keyed by `team:` owner name
+    pub teams: BTreeMap<String, TeamImpact>,
+    /// Services without a team owner. Kept out of `teams` so that no real
+    /// team name can collide with it.
+    pub no_team: Option<TeamImpact>,
+}
+
+impl TeamBreakdown {
+    pub fn is_empty(&self) -> bool {
+        self.teams.is_empty() && self.no_team.is_none()
+    }
+}
+
+/// How a file changed in a diff, from `git diff` status letters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileStatus {
+    Added,
+    Modified,
+    /// Only listed with `--include-deleted`
+    Deleted,
+    /// New path of a rename
+    Renamed,
+    /// Old path of a rename; it counts against the service that owned it
+    RenamedFrom,
+    /// New path of a copy
+    Copied,
+}
+
+/// One service's share of an `ImpactReport`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServiceImpact {
+    /// Number of changed files mapped to the service
+    pub count: usize,
+    pub files: Vec<String>,
+    /// Owners from the service's entry, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owners: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<Contact>,
+    /// Set when unchanged services are listed too
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub impacted: Option<bool>,
+}
+
+impl ServiceImpact {
+    /// `files` with owners and contact taken from `def`.
+    pub fn new(files: Vec<String>, def: Option<&ServiceDef>) -> Self {
+        Self {
+            count: files.len(),
+            files,
+            owners: def.map(|d| d.owners.iter().flatten().map(|o| o.to_string()).collect()),
+            contact: def.and_then(|d| d.contact.clone()),
+            impacted: None,
+        }
+    }
+}
+
+/// A rename whose old and new path belong to the same service.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct MoveWithin {
+    pub service: String,
+    pub from: String,
+    pub to: String,
+}
+
+/// A rename that changes ownership; `None` means unmapped.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct MoveAcross {
+    pub from: String,
+    pub from_service: Option<String>,
+    pub to: String,
+    pub to_service: Option<String>,
+}
+
+impl fmt::Display for MoveAcross {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}) -> {} ({})",
+            self.from,
+            self.from_service.as_deref().unwrap_or("unmapped"),
+            self.to,
+            self.to_service.as_deref().unwrap_or("unmapped")
+        )
+    }
+}
+
+/// Unmapped files under one top-level directory
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct UnmappedGroup {
+    /// Top-level directory with a trailing `/`, or `./` for files at the root
+    pub dir: String,
+    pub count: usize,
+}
+
+/// The services one commit touches: a row of `impacted --commits --format json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct CommitImpact {
+    /// Full commit hash
+    pub sha: String,
+    /// Canonical names of the services the commit's files map to, sorted
+    pub services: Vec<String>,
+}
+
+/// A rule by service, pattern and location
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RuleRef {
+    pub service: String,
+    pub pattern: String,
+    /// 1-based line of the rule
+    pub line: usize,
+    /// File the rule was read from, if it came from a file
+    pub source: Option<PathBuf>,
+}
+
+impl From<&ExplainMatch<'_>> for RuleRef {
+    fn from(m: &ExplainMatch<'_>) -> Self {
+        Self {
+            service: m.service.to_string(),
+            pattern: m.pattern.clone(),
+            line: m.line,
+            source: m.source.map(Path::to_path_buf),
+        }
+    }
+}
+
+/// A path and its service: a row of `who-owns --format json` for several paths
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WhoOwnsRow {
+    pub path: String,
+    /// Canonical service, or `None` when unmapped
+    pub service: Option<String>,
+}
+
+/// `who-owns PATH --format json` payload
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct WhoOwnsReport {
+    pub schema_version: u32,
+    pub path: String,
+    /// Canonical service, or `None` when unmapped
+    pub service: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owners: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contact: Option<Contact>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docs: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runbook: Option<String>,
+    /// Every matching rule, with `--explain`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matches: Option<Vec<RuleMatch>>,
+    /// Number of matching rules, with `--count-matches`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub match_count: Option<usize>,
+    /// Closest directory rule for an unmapped path, with `--nearest`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nearest: Option<RuleRef>,
+}
+
+/// One rule in `WhoOwnsReport::matches`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RuleMatch {
+    pub pattern: String,
+    pub normalized: String,
+    pub service: String,
+    pub line: usize,
+    pub source: Option<PathBuf>,
+    pub priority: i32,
+    /// The rule decides the path
+    pub chosen: bool,
+    /// The mapper's default service, not a rule
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub fallback: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excluded_by: Option<ExclusionRef>,
+    /// With `--specificity`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specificity: Option<usize>,
+}
+
+impl RuleMatch {
+    /// `m` as reported, with its specificity when `specificity` is set.
+    pub fn new(m: &ExplainMatch<'_>, specificity: bool) -> Self {
+        Self {
+            pattern: m.pattern.clone(),
+            normalized: m.normalized.to_string(),
+            service: m.service.to_string(),
+            line: m.line,
+            source: m.source.map(Path::to_path_buf),
+            priority: m.priority,
+            chosen: m.chosen,
+            fallback: m.fallback,
+            excluded_by: m.excluded_by.map(|e| ExclusionRef {
+                pattern: e.pattern.clone(),
+                line: e.line,
+                source: e.source().map(Path::to_path_buf),
+            }),
+            specificity: specificity.then_some(m.specificity),
+        }
+    }
+}
+
+/// The `!pattern` line that excludes a match
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExclusionRef {
+    pub pattern: String,
+    pub line: usize,
+    pub source: Option<PathBuf>,
+}
+
+/// `why-unmapped --format json` payload
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum WhyUnmappedReport {
+    /// The path is mapped after all
+    Mapped {
+        schema_version: u32,
+        path: String,
+        service: String,
+        pattern: String,
+        line: usize,
+    },
+    Unmapped {
+        schema_version: u32,
+        path: String,
+        /// Always `None`
+        service: Option<String>,
+        /// The root scope the path falls outside of, if it does
+        out_of_scope: Option<String>,
+        /// Listed in the ignore file
+        ignored: bool,
+        near_misses: Vec<NearMissRow>,
+        /// `pattern service` line that would map the path
+        suggested_rule: String,
+    },
+}
+
+/// One entry of `WhyUnmappedReport::Unmapped::near_misses`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct NearMissRow {
+    pub pattern: String,
+    pub service: String,
+    pub line: usize,
+    pub source: Option<PathBuf>,
+    pub shared_segments: usize,
+}
+
+impl From<&NearMiss<'_>> for NearMissRow {
+    fn from(m: &NearMiss<'_>) -> Self {
+        Self {
+            pattern: m.pattern.to_string(),
+            service: m.service.to_string(),
+            line: m.line,
+            source: m.source.map(Path::to_path_buf),
+            shared_segments: m.shared_segments,
+        }
+    }
+}
+
+/// A changed file's deciding rule: a row of `explain-diff --format json`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ExplainDiffRow {
+    pub path: String,
+    pub service: Option<String>,
+    /// The winning rule; `None` when unmapped or decided by the fallback
+    pub pattern: Option<String>,
+    pub line: Option<usize>,
+    pub source: Option<PathBuf>,
+    /// Decided by the mapper's default service
+    pub fallback: bool,
+    /// The other matching rules, in the order they would take over
+    pub runner_up: Vec<RuleRef>,
+}
+
+impl ExplainDiffRow {
+    /// The row for `path` from its `ServiceMapper::ranked_matches`.
+    pub fn new(path: &str, matches: &[ExplainMatch<'_>]) -> Self {
+        let winner = matches.first();
+        let rule = winner.filter(|m| !m.fallback);
+        Self {
+            path: path.to_string(),
+            service: winner.map(|m| m.service.to_string()),
+            pattern: rule.map(|m| m.pattern.clone()),
+            line: rule.map(|m| m.line),
+            source: rule.and_then(|m| m.source.map(Path::to_path_buf)),
+            fallback: winner.is_some_and(|m| m.fallback),
+            runner_up: matches.iter().skip(1).map(RuleRef::from).collect(),
+        }
+    }
+}
+
+/// A service's contacts: a row of `contacts --format json`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ContactRow {
+    pub service: String,
+    /// The service has an entry in services.yaml
+    pub declared: bool,
+    /// No contact channel is set
+    pub missing_contact: bool,
+    pub slack: Option<String>,
+    pub email: Option<String>,
+    pub pagerduty: Option<String>,
+    pub oncall_url: Option<String>,
+    pub docs: Option<String>,
+    pub runbook: Option<String>,
+}
+
+impl ContactRow {
+    pub fn new(service: &str, def: Option<&ServiceDef>) -> Self {
+        let contact = def.and_then(|d| d.contact.as_ref());
+        let field = |f: fn(&Contact) -> &Option<String>| contact.and_then(|c| f(c).clone());
+        Self {
+            service: service.to_string(),
+            declared: def.is_some(),
+            missing_contact: contact.is_none_or(|c| c.entries().is_empty()),
+            slack: field(|c| &c.slack),
+            email: field(|c| &c.email),
+            pagerduty: field(|c| &c.pagerduty),
+            oncall_url: field(|c| &c.oncall_url),
+            docs: def.and_then(|d| d.docs.clone()),
+            runbook: def.and_then(|d| d.runbook.clone()),
+        }
+    }
+}
+
+/// `stats --format json` payload
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct StatsReport {
+    pub schema_version: u32,
+    /// Number of rules
+    pub rules: usize,
+    /// Number of distinct services
+    pub services: usize,
+    pub patterns: PatternCounts,
+    pub average_specificity: f64,
+    /// Services with the most rules
+    pub top_services: Vec<ServiceRules>,
+    /// Rules matching the most files, with `--check-matches`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub most_used_rules: Option<Vec<RuleUsage>>,
+    /// Rules matching no file, with `--check-matches`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unused_rules: Option<Vec<RuleUsage>>,
+}
+
+/// Rules by kind of pattern, in `StatsReport`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct PatternCounts {
+    /// Containing `**`
+    pub recursive: usize,
+    /// Without wildcards
+    pub literal: usize,
+    /// Any other wildcard
+    pub wildcard: usize,
+}
+
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServiceRules {
+    pub service: String,
+    pub rules: usize,
+}
+
+/// A rule and the number of walked files it matches
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RuleUsage {
+    pub pattern: String,
+    pub service: String,
+    pub line: usize,
+    pub files: usize,
+}
+
+/// Outcome of linting a SERVICEOWNERS file: the `lint --format json` payload
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct LintResult {
+    pub schema_version: u32,
+    /// No errors were found
+    pub valid: bool,
+    pub warnings: Vec<Diagnostic>,
+    pub errors: Vec<Diagnostic>,
+    /// Rules deleted by `lint --fix`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removed: Option<Vec<RemovedRule>>,
+}
+
+/// A rule deleted by `lint --fix`
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct RemovedRule {
+    pub pattern: String,
+    pub line: usize,
+}
+
+/// Rule lines of a SERVICEOWNERS source with their 1-based line numbers,
+/// trimmed, skipping blanks and comments.
+fn rule_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(idx, line)| (idx + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Parses a rule, or an exclusion (`true`) when the line starts with `!`.
+fn parse_line<'a>(
+    line: &'a str,
+    line_no: usize,
+    separator: Option<&str>,
+    opts: &ParseOptions,
+) -> Result<(bool, ParsedRule<'a>), Diagnostic> {
+    let Some(body) = line.strip_prefix('!') else {
+        return parse_rule(line, line_no, separator, opts).map(|rule| (false, rule));
+    };
+    let rule = parse_rule(body.trim_start(), line_no, separator, opts)?;
+    if !rule.owners.is_empty() || rule.priority != 0 {
+        return Err(Diagnostic {
+            line: Some(line_no),
+            severity: Severity::Error,
+            code: "invalid-exclusion",
+            pattern: None,
+            message: format!(
+                "Invalid line {}: '{}' - exclusions take a pattern and a service, without owners or @priority",
+                line_no, line
+            ),
+        });
+    }
+    Ok((true, rule))
+}
+
+fn build_exclusion_set(exclusions: &[Exclusion]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for exclusion in exclusions {
+        builder.add(exclusion.glob.clone());
+    }
+    builder.build().context("Failed to build exclusion set")
+}
+
+/// One SERVICEOWNERS rule, parsed and compiled.
+struct ParsedRule<'a> {
+    pattern: &'a str,
+    service: &'a str,
+    owners: Vec<&'a str>,
+    priority: i32,
+    normalized: String,
+    glob: globset::Glob,
+}
+
+/// Parses `pattern service [@owner ...] [@priority:N]`. Errors come back as
+/// diagnostics so `validate_source` can collect them all.
+fn parse_rule<'a>(
+    line: &'a str,
+    line_no: usize,
+    separator: Option<&str>,
+    opts: &ParseOptions,
+) -> Result<ParsedRule<'a>, Diagnostic> {
+    let error = |code: &'static str, message: String| Diagnostic {
+        line: Some(line_no),
+        severity: Severity::Error,
+        code,
+        pattern: None,
+        message,
+    };
+
+    let split_once: Vec<&str> = match separator {
+        Some(sep) => line.splitn(2, sep).map(str::trim).collect(),
+        None => line.splitn(2, |c: char| c.is_whitespace()).collect(),
+    };
+    if split_once.len() < 2 {
+        let expected = match separator {
+            Some(sep) => format!("pattern {} service", sep),
+            None => "pattern service".to_string(),
+        };
+        return Err(error(
+            "invalid-line",
+            format!(
+                "Invalid line {}: '{}' - expected '{}'",
+                line_no, line, expected
+            ),
+        ));
+    }
+    let raw_pattern = split_once[0];
+    // `/` alone is the whole repo; `./` or an empty field names nothing
+    let stripped = raw_pattern.trim_start_matches("./");
+    if stripped.is_empty() || (stripped.trim_matches('/').is_empty() && stripped != "/") {
+        return Err(error(
+            "empty-pattern",
+            format!("Invalid line {}: '{}' - empty pattern", line_no, line),
+        ));
+    }
+    let (service, mut owners) = split_inline_owners(split_once[1].trim());
+    let mut priority = None;
+    for token in &owners {
+        let Some(value) = token.strip_prefix("@priority:") else {
+            continue;
+        };
+        if priority.is_some() {
+            return Err(error(
+                "invalid-priority",
+                format!(
+                    "Invalid line {}: '{}' - more than one @priority",
+                    line_no, line
+                ),
+            ));
+        }
+        let value = value.parse::<i32>().map_err(|_| {
+            error(
+                "invalid-priority",
+                format!(
+                    "Invalid line {}: '{}' - @priority needs an integer",
+                    line_no, token
+                ),
+            )
+        })?;
+        priority = Some(value);
+    }
+    owners.retain(|o| !o.starts_with("@priority:"));
+    if service.is_empty() {
+        return Err(error(
+            "missing-service",
+            format!(
+                "Invalid line {}: '{}' - expected a service name before the owners",
+                line_no, line
+            ),
+        ));
+    }
+    if let Some(bad) = owners.iter().find(|o| !o.starts_with('@')) {
+        return Err(error(
+            "invalid-owner",
+            format!(
+                "Invalid line {}: '{}' - inline owners must start with '@'",
+                line_no, bad
+            ),
+        ));
+    }
+
+    let normalized = normalize_pattern(raw_pattern).map_err(|e| {
+        error(
+            "invalid-glob",
+            format!(
+                "Invalid glob pattern on line {}: {} ({:#})",
+                line_no, raw_pattern, e
+            ),
+        )
+    })?;
+    let glob = build_glob(&normalized, opts).map_err(|e| {
+        error(
+            "invalid-glob",
+            format!(
+                "Invalid glob pattern on line {}: {} ({})",
+                line_no, raw_pattern, e
+            ),
+        )
+    })?;
+    Ok(ParsedRule {
+        pattern: raw_pattern,
+        service,
+        owners,
+        priority: priority.unwrap_or(0),
+        normalized,
+        glob,
+    })
+}
+
+/// Rule globs partitioned by the literal first segment of their pattern.
+#[derive(Debug, Clone)]
+struct RuleIndex {
+    by_prefix: HashMap<String, Partition>,
+    catch_all: Partition,
+}
+
+/// One `GlobSet` and the rule index of each of its globs, ascending
+#[derive(Debug, Clone)]
+struct Partition {
+    set: GlobSet,
+    rules: Vec<usize>,
+}
+
+impl Partition {
+    fn build(globs: &[globset::Glob], rules: Vec<usize>) -> Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for &idx in &rules {
+            builder.add(globs[idx].clone());
+        }
+        let set = builder.build().context("Failed to build glob set")?;
+        Ok(Self { set, rules })
+    }
+}
+
+impl RuleIndex {
+    fn build(globs: &[globset::Glob], normalized: &[String]) -> Result<Self> {
+        let mut by_prefix: HashMap<&str, Vec<usize>> = HashMap::new();
+        let mut catch_all = Vec::new();
+        for (idx, glob) in normalized.iter().enumerate() {
+            match literal_first_segment(glob) {
+                Some(prefix) => by_prefix.entry(prefix).or_default().push(idx),
+                None => catch_all.push(idx),
+            }
+        }
+        log::debug!(
+            "Indexed rules into {} prefix partitions, {} catch-all rules",
+            by_prefix.len(),
+            catch_all.len()
+        );
+        Ok(Self {
+            by_prefix: by_prefix
+                .into_iter()
+                .map(|(prefix, rules)| Ok((prefix.to_string(), Partition::build(globs, rules)?)))
+                .collect::<Result<_>>()?,
+            catch_all: Partition::build(globs, catch_all)?,
+        })
+    }
+
+    /// Indices of every rule matching `path`, ascending like `GlobSet::matches`.
+    fn matches(&self, path: &str) -> Vec<usize> {
+        let mut out = Vec::new();
+        self.matches_into(path, &mut out);
+        out
+    }
+
+    /// `matches` into a reused buffer. A directory query (`src/auth/`) also
+    /// matches the rules naming the directory itself (`src/auth`).
+    fn matches_into(&self, path: &str, out: &mut Vec<usize>) {
+        self.matches_path_into(path, out);
+        if let Some(dir) = directory_itself(path) {
+            let mut itself = Vec::new();
+            self.matches_path_into(dir, &mut itself);
+            if !itself.is_empty() {
+                out.extend(itself);
+                out.sort_unstable();
+                out.dedup();
+            }
+        }
+    }
+
+    fn matches_path_into(&self, path: &str, out: &mut Vec<usize>) {
+        let candidate = Candidate::new(path);
+        self.catch_all.set.matches_candidate_into(&candidate, out);
+        for idx in out.iter_mut() {
+            *idx = self.catch_all.rules[*idx];
+        }
+        let first = path.split('/').next().unwrap_or_default();
+        if let Some(partition) = self.by_prefix.get(first) {
+            let start = out.len();
+            out.extend(
+                partition
+                    .set
+                    .matches_candidate(&candidate)
+                    .into_iter()
+                    .map(|idx| partition.rules[idx]),
+            );
+            if start > 0 && start < out.len() {
+                out.sort_unstable();
+            }
+        }
+    }
+}
+
+/// First segment of a normalized glob when it is a plain name, so the glob
+/// can only match paths starting with that directory or file.
+fn literal_first_segment(glob: &str) -> Option<&str> {
+    let first = glob.split('/').next()?;
+    (!first.is_empty() && !first.contains(['*', '?', '[', '{', '\\'])).then_some(first)
+}
+
+/// Compiles a normalized glob with the same settings the mapper uses.
+fn build_glob(glob_str: &str, opts: &ParseOptions) -> Result<globset::Glob, globset::Error> {
+    GlobBuilder::new(glob_str)
+        .literal_separator(!opts.codeowners_compat) // match / as separator
+        .backslash_escape(true) // `\[` etc. are literals, on every platform
+        .build()
+}
+
+/// A single SERVICEOWNERS pattern compiled on its own, for trying a rule out
+/// before adding it. Matches exactly as it would inside a `ServiceMapper`.
+#[derive(Debug, Clone)]
+pub struct PatternTester {
+    pub normalized: String,
+    matcher: globset::GlobMatcher,
+}
+
+impl PatternTester {
+    pub fn new(raw_pattern: &str, opts: &ParseOptions) -> Result<Self> {
+        let normalized = normalize_pattern(raw_pattern)?;
+        let matcher = build_glob(&normalized, opts)
+            .with_context(|| format!("Invalid glob pattern: {}", raw_pattern))?
+            .compile_matcher();
+        Ok(Self {
+            normalized,
+            matcher,
+        })
+    }
+
+    pub fn is_match(&self, path: &str) -> bool {
+        let path = normalize_query_path(path);
+        self.matcher.is_match(path.as_ref())
+            || directory_itself(&path).is_some_and(|dir| self.matcher.is_match(dir))
+    }
+}
+
+pub fn normalize_pattern(pat: &str) -> Result<String> {
+    // 1. strip
+    let mut s = pat.trim().to_string();
+    if s.is_empty() {
+        return Ok(s);
+    }
+
+    // 2. replace backslash separators, keeping escapes of glob metacharacters (e.g. `\[`)
+    s = replace_backslash_separators(&s);
+
+    // 3. strip leading ./
+    while s.starts_with("./") {
+        s = s[2..].to_string();
+    }
+
+    // 4. strip leading / (anchor to root is implied by 'contains slash' logic + absolute match)
+    if s.starts_with('/') {
+        s = s[1..].to_string();
+    }
+
+    if s.is_empty() {
+        return Ok("**".to_string());
+    }
+
+    // 4b. tidy segments: drop empty and `.` ones, collapse `**/**`, resolve `..`.
+    // A pattern written with a directory stays anchored even if `..` removes it.
+    let anchored = s.contains('/');
+    s = resolve_segments(pat, &s)?;
+
+    // 5. trailing slash => /**
+    if s.ends_with('/') {
+        s.pop(); // remove /
+        s.push_str("/**");
+    }
+
+    // 6. If no slash, prepend **/
+    if !anchored && s != "**" {
+        s = format!("**/{}", s);
+    }
+
+    Ok(s)
+}
+
+/// Rewrites `a//./b/../**/**/c` as `a/**/c`; a `..` leaving the root or after
+/// `**` is an error.
+fn resolve_segments(pat: &str, s: &str) -> Result<String> {
+    let trailing = s.ends_with('/');
+    let mut out: Vec<&str> = Vec::new();
+    for seg in s.split('/') {
+        match seg {
+            "" | "." => {}
+            ".." => match out.pop() {
+                Some("**") => anyhow::bail!("'..' after '**' is ambiguous in pattern '{}'", pat),
+                Some(_) => {}
+                None => anyhow::bail!("pattern '{}' escapes the repository root", pat),
+            },
+            "**" if out.last() == Some(&"**") => {}
+            _ => out.push(seg),
+        }
+    }
+    if out.is_empty() {
+        anyhow::bail!(
+            "pattern '{}' resolves to the repository root; use '/' to match everything",
+            pat
+        );
+    }
+    let mut joined = out.join("/");
+    if trailing {
+        joined.push('/');
+    }
+    Ok(joined)
+}
+
+/// Treats `\` as a path separator except before `[ ] { }`, which it escapes.
+fn replace_backslash_separators(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.peek() {
+            Some(&next) if "[]{}".contains(next) => {
+                out.push(c);
+                out.push(next);
+                chars.next();
+            }
+            _ => out.push('/'),
+        }
+    }
+    out
+}
+
+/// Options for `init_from_codeowners_with`.
+#[derive(Debug, Clone, Default)]
+pub struct InitOptions {
+    /// Group rules under `# === service ===` headers, sorted by service then
+    /// pattern, instead of keeping CODEOWNERS order.
+    pub group_by_service: bool,
+    /// Extra directory names to skip when inferring service names, on top of
+    /// `DEFAULT_STOP_WORDS`.
+    pub stop_words: Vec<String>,
+    /// Keep the case of inferred names instead of lowercasing them.
+    pub keep_case: bool,
+}
+
+/// Directory names that never make a good service name.
+pub const DEFAULT_STOP_WORDS: &[&str] = &["src", "lib", "packages", "apps"];
+
+pub fn init_from_codeowners(codeowners_path: &Path) -> Result<String> {
+    init_from_codeowners_with(codeowners_path, &InitOptions::default())
+}
+
+pub fn init_from_codeowners_with(codeowners_path: &Path, opts: &InitOptions) -> Result<String> {
+    let content = fs::read_to_string(codeowners_path)
+        .with_context(|| format!("Failed to read CODEOWNERS at {:?}", codeowners_path))?;
+
+    let mut rules = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.len() < 2 {
+            continue;
+        }
+
+        let pattern = parts[0];
+        let owners = &parts[1..];
+
+        let service = infer_service_name(pattern, owners, opts);
+        rules.push((serviceowners_pattern(pattern), service));
+    }
+
+    let mut out = String::new();
+    out.push_str("# Generated from CODEOWNERS by serviceowners (init)\n");
+    if !opts.group_by_service {
+        out.push_str("# pattern            service\n");
+        for (pattern, service) in &rules {
+            out.push_str(&format!("{:<20} {}\n", pattern, service));
+        }
+        return Ok(out);
+    }
+
+    // Reordering changes which rule wins where patterns overlap (last match wins).
+    out.push_str("# Grouped by service; rule order differs from CODEOWNERS, so review overlaps.\n");
+    rules.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    let mut current: Option<&str> = None;
+    for (pattern, service) in &rules {
+        if current != Some(service.as_str()) {
+            out.push_str(&format!("\n# === {} ===\n", service));
+            current = Some(service);
+        }
+        out.push_str(&format!("{:<20} {}\n", pattern, service));
+    }
+    Ok(out)
+}
+
+/// A CODEOWNERS pattern in SERVICEOWNERS syntax (a bare `dir/` becomes `**/dir/`).
+fn serviceowners_pattern(pattern: &str) -> String {
+    match pattern.strip_suffix('/') {
+        Some(dir) if !dir.is_empty() && !dir.contains('/') => format!("**/{}", pattern),
+        _ => pattern.to_string(),
+    }
+}
+
+fn infer_service_name(pattern: &str, owners: &[&str], opts: &InitOptions) -> String {
+    let p = pattern.trim_start_matches('/').trim_end_matches('/');
+    let segments: Vec<&str> = p.split('/').collect();
+    let is_stop_word =
+        |s: &str| DEFAULT_STOP_WORDS.contains(&s) || opts.stop_words.iter().any(|w| w == s);
+    let case = |name: String| {
+        if opts.keep_case {
+            name
+        } else {
+            name.to_lowercase()
+        }
+    };
+
+    let candidates: Vec<&str> = segments
+        .iter()
+        .filter(|&&s| s != "*" && s != "**" && !is_stop_word(s))
+        .cloned()
+        .collect();
+
+    if let Some(last) = candidates.last() {
+        let name = last.replace(|c: char| !c.is_alphanumeric(), "_");
+        if !name.is_empty() {
+            return case(name);
+        }
+    }
+
+    if let Some(owner) = owners.first() {
+        let o = owner.trim_start_matches('@');
+        if let Some((_, name)) = o.split_once('/') {
+            return case(name.replace('-', "_"));
+        }
+        return case(o.replace('-', "_"));
+    }
+
+    "unknown_service".to_string()
+}
+
+/// Writes a normalized glob back in CODEOWNERS syntax (`dir/**` becomes `/dir/`).
+pub fn codeowners_pattern(normalized: &str) -> String {
+    if normalized == "**" {
+        return "*".to_string();
+    }
+    if let Some(rest) = normalized.strip_prefix("**/") {
+        if !rest.contains('/') {
+            return rest.to_string();
+        }
+        if let Some(dir) = rest.strip_suffix("/**").filter(|d| !d.contains('/')) {
+            return format!("{}/", dir);
+        }
+        return normalized.to_string();
+    }
+    match normalized.strip_suffix("/**") {
+        Some(dir) if !dir.contains('*') => format!("/{}/", dir),
+        _ => format!("/{}", normalized),
+    }
+}
+
+/// Renders a CODEOWNERS file with one line per rule and its service's owners,
+/// sorted by `@priority`. Exclusions are left out.
+pub fn export_codeowners(mapper: &ServiceMapper, services: &ServicesFile) -> String {
+    let mut order: Vec<usize> = (0..mapper.len()).collect();
+    order.sort_by_key(|&idx| mapper.priority(idx));
+
+    let mut out = String::new();
+    out.push_str("# Generated from SERVICEOWNERS by serviceowners (export-codeowners)\n");
+    if !mapper.exclusions().is_empty() {
+        out.push_str(&format!(
+            "# {} !pattern exclusion(s) can't be expressed in CODEOWNERS and were left out.\n",
+            mapper.exclusions().len()
+        ));
+    }
+    if let Some(default) = mapper.default_service() {
+        // First, so every rule below takes precedence over it
+        let owners = services
+            .get(default)
+            .and_then(|def| def.owners.as_deref())
+            .unwrap_or_default();
+        let names: Vec<String> = owners.iter().map(Owner::mention).collect();
+        if names.is_empty() {
+            out.push_str(&format!("# {}: no owners defined\n*\n", default));
+        } else {
+            out.push_str(&format!("{:<20} {}\n", "*", names.join(" ")));
+        }
+    }
+    for idx in order {
+        let service = &mapper.service_names[idx];
+        let owners = services
+            .get(service)
+            .and_then(|def| def.owners.as_deref())
+            .filter(|owners| !owners.is_empty())
+            .unwrap_or_else(|| mapper.rule_owners(idx));
+        let pattern = codeowners_pattern(&mapper.normalized[idx]);
+        if owners.is_empty() {
+            out.push_str(&format!("# {}: no owners defined\n{}\n", service, pattern));
+            continue;
+        }
+        let names: Vec<String> = owners.iter().map(Owner::mention).collect();
+        out.push_str(&format!("{:<20} {}\n", pattern, names.join(" ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapper(rules: &str) -> ServiceMapper {
+        ServiceMapper::parse(rules).expect("rules parse")
+    }
+
+    fn services(yaml: &str) -> ServicesFile {
+        serde_yaml::from_str(yaml).expect("services parse")
+    }
+
+    fn owner_names(owners: Option<&[Owner]>) -> Option<Vec<String>> {
+        owners.map(|owners| owners.iter().map(Owner::to_string).collect())
+    }
+
+    #[test]
+    fn bracket_range_matches_one_character() {
+        let m = mapper("src/[a-c]*/** abc\n");
+        assert_eq!(m.find_service("src/api/x.rs"), Some("abc"));
+        assert_eq!(m.find_service("src/core/x.rs"), Some("abc"));
+        assert_eq!(m.find_service("src/data/x.rs"), None);
+        assert_eq!(m.find_service("src/Api/x.rs"), None);
+    }
+
+    #[test]
+    fn escaped_brackets_are_literal() {
+        let m = mapper("src/foo\\[bar\\]/** foo\n");
+        assert_eq!(m.normalized_pattern(0), "src/foo\\[bar\\]/**");
+        assert_eq!(m.find_service("src/foo[bar]/x.rs"), Some("foo"));
+        assert_eq!(m.find_service("src/foob/x.rs"), None);
+        assert_eq!(m.find_service("src/foor/x.rs"), None);
+    }
+
+    #[test]
+    fn backslash_before_other_characters_is_a_separator() {
+        let m = mapper("src\\auth\\** auth\n");
+        assert_eq!(m.normalized_pattern(0), "src/auth/**");
+        assert_eq!(m.find_service("src/auth/x.rs"), Some("auth"));
+    }
+
+    const RESOLVER_RULES: &str = "\
+src/** backend
+src/auth/** auth @alice
+docs/** docs
+legacy/** old-billing
+";
+
+    const RESOLVER_SERVICES: &str = "\
+services:
+  backend:
+    owners: [\"@org/backend\"]
+  docs:
+    docs: https://example.com/docs
+  billing:
+    owners: [\"@org/billing\"]
+    aliases: [old-billing]
+";
+
+    fn resolver() -> OwnershipResolver {
+        OwnershipResolver::new(mapper(RESOLVER_RULES), services(RESOLVER_SERVICES))
+    }
+
+    #[test]
+    fn resolver_uses_services_yaml_owners() {
+        let r = resolver();
+        assert_eq!(r.service_for_path("src/main.rs"), Some("backend"));
+        assert_eq!(
+            owner_names(r.owners_for_path("src/main.rs")),
+            Some(vec!["@org/backend".to_string()])
+        );
+    }
+
+    #[test]
+    fn resolver_falls_back_to_inline_owners_for_missing_service() {
+        let r = resolver();
+        assert_eq!(r.service_for_path("src/auth/a.rs"), Some("auth"));
+        assert!(r.service_def("auth").is_none());
+        assert_eq!(
+            owner_names(r.owners_for_path("src/auth/a.rs")),
+            Some(vec!["@alice".to_string()])
+        );
+    }
+
+    #[test]
+    fn resolver_has_no_owners_for_missing_service_without_inline_owners() {
+        let r = OwnershipResolver::new(mapper("src/** backend\n"), services("services: {}\n"));
+        assert_eq!(r.service_for_path("src/main.rs"), Some("backend"));
+        assert!(r.service_def("backend").is_none());
+        assert!(r.owners_for_path("src/main.rs").is_none());
+    }
+
+    #[test]
+    fn resolver_has_no_owners_for_declared_service_without_owners() {
+        let r = resolver();
+        assert_eq!(r.service_for_path("docs/index.md"), Some("docs"));
+        assert!(r.service_def("docs").is_some());
+        assert!(r.owners_for_path("docs/index.md").is_none());
+    }
+
+    #[test]
+    fn resolver_resolves_aliases_to_the_canonical_service() {
+        let r = resolver();
+        assert_eq!(r.service_for_path("legacy/pay.rs"), Some("billing"));
+        assert_eq!(
+            owner_names(r.owners_for_path("legacy/pay.rs")),
+            Some(vec!["@org/billing".to_string()])
+        );
+    }
+
+    #[test]
+    fn resolver_returns_none_for_unmapped_paths() {
+        let r = resolver();
+        assert_eq!(r.service_for_path("README.md"), None);
+        assert!(r.owners_for_path("README.md").is_none());
+    }
+
+    #[test]
+    fn nearest_service_normalizes_the_path() {
+        let m = mapper("src/*.rs backend\ndocs/** docs\n");
+        let nearest = |path| m.nearest_service(path).map(|n| (n.service, n.line));
+        assert_eq!(nearest("src/new/thing.rs"), Some(("backend", 1)));
+        for path in [
+            "./src/new/thing.rs",
+            "src//new/thing.rs",
+            "/src/./new/thing.rs",
+        ] {
+            assert_eq!(nearest(path), nearest("src/new/thing.rs"), "{}", path);
+        }
+    }
+
+    #[test]
+    fn unicode_paths_match() {
+        let m = mapper("src/café/** cafe\n**/*.ñ enye\ndocs/日本語/ ja\n");
+        assert_eq!(m.find_service("src/café/naïve.rs"), Some("cafe"));
+        assert_eq!(m.find_service("./src/café/naïve.rs"), Some("cafe"));
+        assert_eq!(m.find_service("src/cafe/naive.rs"), None);
+        assert_eq!(m.find_service("lib/año.ñ"), Some("enye"));
+        assert_eq!(m.find_service("docs/日本語/index.md"), Some("ja"));
+    }
+
+    #[test]
+    fn query_paths_are_normalized() {
+        for (path, expected) in [
+            ("src/auth/mod.rs", "src/auth/mod.rs"),
+            ("./src/auth/mod.rs", "src/auth/mod.rs"),
+            ("/src/auth/mod.rs", "src/auth/mod.rs"),
+            ("src//auth/./mod.rs", "src/auth/mod.rs"),
+            ("src/auth/", "src/auth/"),
+            ("src/auth//", "src/auth/"),
+            ("./src/auth/./", "src/auth/"),
+        ] {
+            assert_eq!(normalize_query_path(path), expected, "{}", path);
+        }
+    }
+
+    #[test]
+    fn directory_query_matches_directory_rules() {
+        let m = mapper("src/** backend\nsrc/auth/** auth\n");
+        assert_eq!(m.find_service("src/auth/"), Some("auth"));
+        assert_eq!(m.find_service("./src/auth/mod.rs"), Some("auth"));
+    }
+
+    #[test]
+    fn directory_query_matches_a_rule_for_the_directory_itself() {
+        let m = mapper("src/** backend\nsrc/auth auth\n");
+        assert_eq!(m.find_service("src/auth"), Some("auth"));
+        assert_eq!(m.find_service("src/auth/"), Some("auth"));
+        assert_eq!(m.find_service("src/auth/mod.rs"), Some("backend"));
+        assert!(PatternTester::new("src/auth", &ParseOptions::default())
+            .unwrap()
+            .is_match("src/auth/"));
+    }
+
+    #[test]
+    fn concurrent_lookups_share_one_mapper() {
+        let m = Arc::new(mapper("src/** backend\nsrc/auth/** auth\ndocs/** docs\n"));
+        let expected = |i: usize| match i % 4 {
+            0 => (format!("src/auth/{}.rs", i), Some("auth")),
+            1 => (format!("src/{}.rs", i), Some("backend")),
+            2 => (format!("docs/{}.md", i), Some("docs")),
+            _ => (format!("other/{}.txt", i), None),
+        };
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let m = Arc::clone(&m);
+                scope.spawn(move || {
+                    for i in (thread..2000).step_by(8) {
+                        let (path, service) = expected(i);
+                        assert_eq!(m.find_service(&path), service, "{}", path);
+                        assert_eq!(m.explain_service(&path).is_empty(), service.is_none());
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn readers_see_a_whole_mapper_across_a_reload() {
+        let shared = Arc::new(std::sync::RwLock::new(mapper("src/** old\n")));
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let shared = Arc::clone(&shared);
+                scope.spawn(move || {
+                    for _ in 0..1000 {
+                        let m = shared.read().unwrap();
+                        let service = m.find_service("src/main.rs");
+                        assert!(matches!(service, Some("old" | "new")), "{:?}", service);
+                    }
+                });
+            }
+            let reloaded = mapper("src/** new\n");
+            *shared.write().unwrap() = reloaded;
+        });
+        assert_eq!(
+            shared.read().unwrap().find_service("src/main.rs"),
+            Some("new")
+        );
+    }
+
+    /// The one diagnostic `validate_source` reports, checked against the
+    /// expected code, line and severity, and against `DIAGNOSTIC_CODES`
+    fn assert_diagnostic(
+        diags: Vec<Diagnostic>,
+        code: &str,
+        line: Option<usize>,
+        severity: Severity,
+    ) {
+        assert_eq!(diags.len(), 1, "{:?}", diags);
+        let diag = &diags[0];
+        assert_eq!(
+            (diag.code, diag.line, diag.severity),
+            (code, line, severity),
+            "{}",
+            diag.message
+        );
+        assert!(DIAGNOSTIC_CODES.iter().any(|(c, _)| *c == code));
+    }
+
+    fn rules_diagnostics(rules: &str) -> Vec<Diagnostic> {
+        ServiceMapper::validate_source(rules, &ParseOptions::default())
+    }
+
+    #[test]
+    fn diagnostic_invalid_line() {
+        let diags = rules_diagnostics("src/** api\nlonely\n");
+        assert_diagnostic(diags, "invalid-line", Some(2), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_empty_pattern() {
+        let diags = rules_diagnostics("# rules\n./ api\n");
+        assert_diagnostic(diags, "empty-pattern", Some(2), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_missing_service() {
+        let diags = rules_diagnostics("src/** @team\n");
+        assert_diagnostic(diags, "missing-service", Some(1), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_owner() {
+        let diags = rules_diagnostics("src/** api @team bob\n");
+        assert_diagnostic(diags, "invalid-owner", Some(1), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_priority() {
+        let diags = rules_diagnostics("src/** api @priority:high\n");
+        assert_diagnostic(diags, "invalid-priority", Some(1), Severity::Error);
+        let diags = rules_diagnostics("src/** api @priority:1 @priority:2\n");
+        assert_diagnostic(diags, "invalid-priority", Some(1), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_glob() {
+        let diags = rules_diagnostics("src/** api\nsrc/[a** web\n");
+        assert_diagnostic(diags, "invalid-glob", Some(2), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_exclusion() {
+        let diags = rules_diagnostics("src/** api\n!src/gen/** api @team\n");
+        assert_diagnostic(diags, "invalid-exclusion", Some(2), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_file() {
+        let diags = ServicesFile::validate_source("services:\n  api:\n    owners: 5\n");
+        assert_diagnostic(diags, "invalid-file", Some(3), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_invalid_yaml() {
+        let diags = ServicesFile::validate_source("services:\n  api: [\n");
+        assert_diagnostic(diags, "invalid-yaml", Some(3), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_unknown_key() {
+        let diags = ServicesFile::validate_source("services:\n  api:\n    slak: '#api'\n");
+        assert_diagnostic(diags, "unknown-key", Some(3), Severity::Error);
+    }
+
+    #[test]
+    fn diagnostic_no_rules() {
+        let diags = rules_diagnostics("# nothing yet\n");
+        assert_diagnostic(diags, "no-rules", None, Severity::Warning);
+    }
+
+    #[test]
+    fn diagnostic_duplicate() {
+        let diags = rules_diagnostics("src/** api\nsrc/** api\n");
+        assert_diagnostic(diags, "duplicate", Some(2), Severity::Warning);
+    }
+
+    #[test]
+    fn diagnostic_conflict() {
+        let diags = rules_diagnostics("src/** api\nsrc/** web\n");
+        assert_diagnostic(diags, "conflict", Some(2), Severity::Warning);
+    }
+
+    #[test]
+    fn diagnostic_shadowed() {
+        let diags = rules_diagnostics("src/api/** api\nsrc/** core\n");
+        assert_diagnostic(diags, "shadowed", Some(1), Severity::Warning);
+    }
+
+    #[test]
+    fn diagnostic_unused_exclusion() {
+        let diags = rules_diagnostics("src/** api\n!src/gen/** web\n");
+        assert_diagnostic(diags, "unused-exclusion", Some(2), Severity::Warning);
+    }
+
+    #[test]
+    fn merge_keeps_the_default_service_set_on_any_input() {
+        let a = mapper("src/** core\n");
+        let b = mapper("docs/** docs\n").with_default_service("platform");
+        let merged = ServiceMapper::from_mappers([a, b]).unwrap();
+        assert_eq!(merged.default_service(), Some("platform"));
+        assert_eq!(merged.find_service("tools/x.sh"), Some("platform"));
+    }
+
+    #[test]
+    fn merge_rejects_conflicting_defaults_and_strategies() {
+        let a = mapper("src/** core\n").with_default_service("platform");
+        let b = mapper("docs/** docs\n").with_default_service("docs");
+        let err = ServiceMapper::from_mappers([a, b]).unwrap_err();
+        assert!(err.to_string().contains("different default services"));
+
+        let a = mapper("src/** core\n").with_match_strategy(MatchStrategy::FirstWins);
+        let b = mapper("docs/** docs\n");
+        let err = a.merge(b).unwrap_err();
+        assert!(err.to_string().contains("different match strategies"));
+
+        let a = mapper("src/** core\n").with_match_strategy(MatchStrategy::FirstWins);
+        let b = mapper("docs/** docs\n").with_match_strategy(MatchStrategy::FirstWins);
+        let merged = a.merge(b).unwrap();
+        assert_eq!(merged.match_strategy(), MatchStrategy::FirstWins);
+    }
+
+    #[test]
+    fn normalize_pattern_table() {
+        let cases = [
+            ("/src/auth", "src/auth"),
+            ("/src/auth/", "src/auth/**"),
+            ("src/auth/", "src/auth/**"),
+            ("  docs/  ", "docs/**"),
+            ("Makefile", "**/Makefile"),
+            ("*.md", "**/*.md"),
+            ("build/", "build/**"),
+            ("./src/**", "src/**"),
+            ("././docs/*.md", "docs/*.md"),
+            ("/", "**"),
+            ("**", "**"),
+            ("src/./api//x.rs", "src/api/x.rs"),
+            ("src/../docs/**", "docs/**"),
+            ("tools/../Makefile", "Makefile"),
+            ("a/**/**/b", "a/**/b"),
+            ("a//./b/../**/**/c", "a/**/c"),
+            ("src\\auth\\**", "src/auth/**"),
+            ("src/foo\\[bar\\]", "src/foo\\[bar\\]"),
+            ("src/{a,b}\\{c\\}", "src/{a,b}\\{c\\}"),
+            ("src\\*.rs", "src/*.rs"),
+        ];
+        for (pattern, expected) in cases {
+            assert_eq!(
+                normalize_pattern(pattern).unwrap(),
+                expected,
+                "pattern {:?}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_pattern_rejects_bad_dot_dot() {
+        let cases = [
+            ("../x", "escapes the repository root"),
+            ("src/../../x", "escapes the repository root"),
+            ("src/**/../x", "ambiguous"),
+            ("src/..", "resolves to the repository root"),
+        ];
+        for (pattern, expected) in cases {
+            let err = normalize_pattern(pattern).unwrap_err();
+            assert!(
+                err.to_string().contains(expected),
+                "pattern {:?}: {}",
+                pattern,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn impacted_groups_files_into_an_impact_report() {
+        let m = mapper("src/api/** api\nsrc/web/** web\n");
+        let files: Vec<String> = ["src/web/b.ts", "README.md", "src/api/x.rs", "src/web/a.ts"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        let report = m.impacted(&files);
+        assert_eq!(report.schema_version, JSON_SCHEMA_VERSION);
+        assert_eq!(report.impacted_services, ["api", "web"]);
+        assert_eq!(
+            report.services["web"].files,
+            ["src/web/b.ts", "src/web/a.ts"]
+        );
+        assert_eq!(report.services["web"].count, 2);
+        assert_eq!(report.services["api"].owners, None);
+        assert_eq!(report.unmapped_files, ["README.md"]);
+        assert!(report.ignored_files.is_empty());
+    }
+
+    const OVERLAPPING_RULES: &str = "\
+src/api/auth/** auth
+src/** core
+src/api/** api
+";
+
+    #[test]
+    fn strategies_pick_different_rules_for_an_overlapping_path() {
+        let m = mapper(OVERLAPPING_RULES);
+        let path = "src/api/auth/login.rs";
+        let mut matches = Vec::new();
+        m.matching_rules_into(path, &mut matches);
+        assert_eq!(matches, [0, 1, 2]);
+        let cases = [
+            (MatchStrategy::LastWins, "api"),
+            (MatchStrategy::FirstWins, "auth"),
+            (MatchStrategy::MostSpecific, "auth"),
+        ];
+        for (strategy, expected) in cases {
+            assert_eq!(
+                m.find_service_with(path, strategy),
+                Some(expected),
+                "{:?}",
+                strategy
+            );
+            let m = m.clone().with_match_strategy(strategy);
+            assert_eq!(m.find_service(path), Some(expected), "{:?}", strategy);
+            let ranked = m.ranked_matches(path);
+            assert_eq!(ranked[0].service, expected, "{:?}", strategy);
+            assert!(ranked[0].chosen && ranked[1..].iter().all(|r| !r.chosen));
+        }
+        // MostSpecific ignores line order
+        let m = mapper("src/** core\nsrc/api/auth/** auth\nsrc/api/** api\n");
+        assert_eq!(
+            m.find_service_with(path, MatchStrategy::MostSpecific),
+            Some("auth")
+        );
+    }
+
+    #[test]
+    fn higher_priority_wins_under_every_strategy() {
+        let m = mapper("src/api/auth/** auth\nsrc/** core @priority:10\nsrc/api/** api\n");
+        for strategy in [
+            MatchStrategy::LastWins,
+            MatchStrategy::FirstWins,
+            MatchStrategy::MostSpecific,
+        ] {
+            assert_eq!(
+                m.find_service_with("src/api/auth/login.rs", strategy),
+                Some("core"),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn strategy_orders_rules_of_equal_priority() {
+        let m = mapper("src/** core @priority:5\nsrc/api/** api @priority:5\nsrc/api/x/** x\n");
+        let path = "src/api/x/y.rs";
+        let cases = [
+            (MatchStrategy::LastWins, "api"),
+            (MatchStrategy::FirstWins, "core"),
+            (MatchStrategy::MostSpecific, "api"),
+        ];
+        for (strategy, expected) in cases {
+            assert_eq!(
+                m.find_service_with(path, strategy),
+                Some(expected),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn negative_priority_loses_to_the_default() {
+        let m = mapper("src/api/** api @priority:-1\nsrc/** core\n");
+        for strategy in [
+            MatchStrategy::LastWins,
+            MatchStrategy::FirstWins,
+            MatchStrategy::MostSpecific,
+        ] {
+            assert_eq!(
+                m.find_service_with("src/api/x.rs", strategy),
+                Some("core"),
+                "{:?}",
+                strategy
+            );
+        }
+        assert_eq!(m.find_service("src/api/x.rs"), Some("core"));
+    }
+
+    #[test]
+    fn by_team_keeps_services_without_a_team_apart() {
+        let m = mapper("src/api/** api\nsrc/web/** web\nsrc/tools/** tools\n");
+        let services = services(
+            "
+services:
+  api:
+    owners: [{team: unowned}, {team: platform}]
+  web:
+    owners: [{user: alice}]
+",
+        );
+        let files: Vec<String> = ["src/api/x.rs", "src/web/a.ts", "src/tools/t.sh"]
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        let breakdown = m.impacted(&files).by_team(Some(&services));
+        let teams: Vec<&str> = breakdown.teams.keys().map(String::as_str).collect();
+        assert_eq!(teams, ["platform", "unowned"]);
+        assert_eq!(breakdown.teams["unowned"].services, ["api"]);
+        assert_eq!(breakdown.teams["platform"].files, ["src/api/x.rs"]);
+        assert!(!breakdown.is_empty());
+        let no_team = breakdown.no_team.expect("services without a team");
+        assert_eq!(no_team.services, ["tools", "web"]);
+        assert_eq!(no_team.count, 2);
+    }
+
+    #[test]
+    fn exclusion_overrides_an_earlier_include() {
+        let m = mapper("backend/** backend\n!backend/vendor/** backend\n");
+        assert_eq!(m.find_service("backend/app.rs"), Some("backend"));
+        assert_eq!(m.find_service("backend/vendor/lib.rs"), None);
+        let explained = m.explain_service("backend/vendor/lib.rs");
+        let excluded = explained[0].excluded_by.expect("excluded match");
+        assert_eq!(
+            (excluded.pattern.as_str(), excluded.line),
+            ("backend/vendor/**", 2)
+        );
+        assert!(!explained[0].chosen);
+    }
+
+    #[test]
+    fn include_after_an_exclusion_readds_the_path_for_other_services_only() {
+        let m =
+            mapper("backend/** backend\n!backend/vendor/** backend\nbackend/vendor/** vendor\n");
+        assert_eq!(m.find_service("backend/vendor/lib.rs"), Some("vendor"));
+        // The exclusion holds for every backend rule, wherever it appears
+        let m = mapper("!backend/vendor/** backend\nbackend/** backend\n");
+        assert_eq!(m.find_service("backend/vendor/lib.rs"), None);
+        assert_eq!(m.find_service("backend/app.rs"), Some("backend"));
+    }
+
+    #[test]
+    fn exclusion_under_a_root_scope() {
+        let opts = ParseOptions {
+            root_scope: Some("services/".to_string()),
+            ..ParseOptions::default()
+        };
+        let m = ServiceMapper::parse_with(
+            "services/** svc\n!services/gen/** svc\nservices/gen/** gen\n!tools/** svc\n",
+            &opts,
+        )
+        .unwrap();
+        assert_eq!(m.find_service("services/api/x.rs"), Some("svc"));
+        assert_eq!(m.find_service("services/gen/x.rs"), Some("gen"));
+        assert_eq!(m.find_service("tools/x.sh"), None);
+        assert!(m.validate().iter().all(|d| d.code != "unused-exclusion"));
+    }
+
+    #[test]
+    fn exclusion_beats_priority_under_every_strategy() {
+        let m = mapper("src/** core @priority:5\n!src/gen/** core\nsrc/gen/** gen\n");
+        for strategy in [
+            MatchStrategy::LastWins,
+            MatchStrategy::FirstWins,
+            MatchStrategy::MostSpecific,
+        ] {
+            assert_eq!(
+                m.find_service_with("src/gen/x.rs", strategy),
+                Some("gen"),
+                "{:?}",
+                strategy
+            );
+            assert_eq!(
+                m.find_service_with("src/lib.rs", strategy),
+                Some("core"),
+                "{:?}",
+                strategy
+            );
+        }
+        let m = mapper("src/gen/** gen @priority:5\nsrc/** core\n!src/gen/** gen\n");
+        for strategy in [
+            MatchStrategy::LastWins,
+            MatchStrategy::FirstWins,
+            MatchStrategy::MostSpecific,
+        ] {
+            assert_eq!(
+                m.find_service_with("src/gen/x.rs", strategy),
+                Some("core"),
+                "{:?}",
+                strategy
+            );
+        }
+    }
+
+    #[test]
+    fn migrate_services_yaml_upgrades_an_unversioned_file() {
+        let v1 = "# services\nservices:\n  payments:\n    owners:\n      - \"@acme/payments\"  # on call\n      - '@alice'\n      - bob@acme.dev\n    slak: S01  # typo, kept\n    aliases: [pay]\n";
+        let migrated = migrate_services_yaml(v1).unwrap().expect("needs migrating");
+        assert_eq!(
+            migrated,
+            "# services\nversion: 2\nservices:\n  payments:\n    owners:\n      - team: \"@acme/payments\"  # on call\n      - user: \"@alice\"\n      - email: \"bob@acme.dev\"\n    slak: S01  # typo, kept\n    aliases: [pay]\n"
+        );
+        let file: ServicesFile = serde_yaml::from_str(&migrated).unwrap();
+        assert_eq!(file.version, Some(SERVICES_SCHEMA_VERSION));
+        assert_eq!(file.resolve("pay").map(|(name, _)| name), Some("payments"));
+        assert_eq!(migrate_services_yaml(&migrated).unwrap(), None);
+    }
+
+    #[test]
+    fn migrate_services_yaml_bumps_version_1() {
+        let v1 = "version: 1\nservices:\n  api:\n    owners: [\"@acme/api\"]\n";
+        assert_eq!(
+            migrate_services_yaml(v1).unwrap().as_deref(),
+            Some("version: 2\nservices:\n  api:\n    owners: [\"@acme/api\"]\n")
+        );
+    }
+
+    #[test]
+    fn migrate_services_yaml_leaves_current_files_alone() {
+        let current = "version: 2\nservices:\n  api:\n    owners:\n      - \"@acme/api\"\n";
+        assert_eq!(migrate_services_yaml(current).unwrap(), None);
+    }
+
+    #[test]
+    fn migrate_services_yaml_keeps_yaml_aliases_untouched() {
+        let v1 = "services:\n  api:\n    owners: &core\n      - \"@acme/core\"\n  web:\n    owners: *core\n";
+        let migrated = migrate_services_yaml(v1).unwrap().expect("needs migrating");
+        assert_eq!(migrated, format!("version: 2\n{}", v1));
+        let file: ServicesFile = serde_yaml::from_str(&migrated).unwrap();
+        let names = |svc: &str| owner_names(file.get(svc).unwrap().owners.as_deref());
+        assert_eq!(names("web"), Some(vec!["@acme/core".to_string()]));
+        assert_eq!(names("web"), names("api"));
+    }
+
+    #[test]
+    fn migrate_services_yaml_rejects_invalid_yaml() {
+        let err = migrate_services_yaml("services: [\n").unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to parse services file"));
+    }
+
+    #[test]
+    fn expand_env_str_substitutes_set_variables() {
+        std::env::set_var("SOWNERS_TEST_SLACK", "C042");
+        assert_eq!(
+            expand_env_str("#${SOWNERS_TEST_SLACK}-alerts").unwrap(),
+            "#C042-alerts"
+        );
+        assert_eq!(expand_env_str("$5 and $HOME").unwrap(), "$5 and $HOME");
+    }
+
+    #[test]
+    fn expand_env_str_escapes_and_errors() {
+        assert_eq!(
+            expand_env_str("$${SOWNERS_TEST_UNSET}").unwrap(),
+            "${SOWNERS_TEST_UNSET}"
+        );
+        let err = expand_env_str("${SOWNERS_TEST_UNSET}").unwrap_err();
+        assert_eq!(err.to_string(), "variable ${SOWNERS_TEST_UNSET} is not set");
+        let err = expand_env_str("a ${SOWNERS_TEST_SLACK").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unterminated '${' in 'a ${SOWNERS_TEST_SLACK'"
+        );
+        assert!(expand_env_str("${}").is_err());
+    }
+
+    #[test]
+    fn expand_env_values_names_the_failing_value() {
+        let mut value: serde_yaml::Value = serde_yaml::from_str(
+            "services:\n  api:\n    docs: https://docs/$${x}\n    owners:\n      - \"@acme/api\"\n      - team: \"${SOWNERS_TEST_UNSET_TEAM}\"\n",
+        )
+        .unwrap();
+        let err = expand_env_values(&mut value, &mut String::new()).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "in 'services.api.owners[1].team': variable ${SOWNERS_TEST_UNSET_TEAM} is not set"
+        );
+        let mut value: serde_yaml::Value =
+            serde_yaml::from_str("services:\n  api:\n    docs: https://docs/$${x}\n").unwrap();
+        expand_env_values(&mut value, &mut String::new()).unwrap();
+        assert_eq!(value["services"]["api"]["docs"], "https://docs/${x}");
+    }
+
+    #[test]
+    fn rule_index_agrees_with_a_linear_scan() {
+        let m = mapper(
+            "/ root\n**/*.md docs\n*.rs rust\n**/gen/** generated\n[ab]pi/** api @priority:1\napi/v1/** v1\n!api/v1/legacy/** v1\nweb/*.ts web\n{web,app}/** frontend\napi/v1/legacy/* legacy\n",
+        );
+        let matchers: Vec<globset::GlobMatcher> =
+            m.globs.iter().map(|g| g.compile_matcher()).collect();
+        let paths = [
+            "README.md",
+            "main.rs",
+            "api/x.go",
+            "bpi/x.go",
+            "cpi/x.go",
+            "api/v1/x.rs",
+            "api/v1/legacy/old.rs",
+            "api/gen/x.rs",
+            "docs/gen/a.md",
+            "web/a.ts",
+            "web/deep/a.ts",
+            "app/a.ts",
+            "other/x",
+        ];
+        for path in paths {
+            let linear: Vec<usize> = (0..m.len())
+                .filter(|&idx| matchers[idx].is_match(path))
+                .collect();
+            assert_eq!(m.index.matches(path), linear, "{}", path);
+            let exclusions = m.matching_exclusions(path);
+            for strategy in [
+                MatchStrategy::LastWins,
+                MatchStrategy::FirstWins,
+                MatchStrategy::MostSpecific,
+            ] {
+                let winner = linear
+                    .iter()
+                    .copied()
+                    .filter(|&idx| m.excluded_by(idx, &exclusions).is_none())
+                    .max_by_key(|&idx| m.precedence(idx, strategy));
+                assert_eq!(
+                    m.winning_rule_with(path, strategy),
+                    winner,
+                    "{} {:?}",
+                    path,
+                    strategy
+                );
+            }
+        }
+        assert_eq!(m.find_service("api/v1/x.rs"), Some("api"));
+        assert_eq!(m.find_service("api/v1/legacy/old.rs"), Some("api"));
+        assert_eq!(m.find_service("cpi/x.go"), Some("root"));
+    }
+
+    #[test]
+    fn export_codeowners_round_trips_through_init() {
+        let original = mapper(
+            "src/** core\nsrc/auth/** auth\n*.md docs\n/Makefile build\n**/gen/** generated\n!src/gen/** generated\n",
+        );
+        let services = services(
+            "services:\n  core:\n    owners: [\"@acme/core\"]\n  auth:\n    owners: [\"@acme/auth\"]\n  docs:\n    owners: [\"@acme/docs\"]\n  build:\n    owners: [\"@acme/build\"]\n  generated:\n    owners: [\"@acme/gen\"]\n",
+        );
+        let exported = export_codeowners(&original, &services);
+        assert!(exported.contains("# 1 !pattern exclusion(s) can't be expressed"));
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("CODEOWNERS");
+        fs::write(&path, &exported).unwrap();
+        let imported = mapper(&init_from_codeowners_with(&path, &InitOptions::default()).unwrap());
+        assert_eq!(imported.normalized, original.normalized);
+
+        let pattern_of = |m: &ServiceMapper, path: &str| {
+            m.winning_rule_with(path, MatchStrategy::LastWins)
+                .map(|idx| m.normalized[idx].clone())
+        };
+        for path in [
+            "src/lib.rs",
+            "src/auth/login.rs",
+            "README.md",
+            "src/auth/README.md",
+            "Makefile",
+            "tools/Makefile",
+            "tools/gen/a.rs",
+            "other.txt",
+        ] {
+            assert_eq!(
+                pattern_of(&imported, path),
+                pattern_of(&original, path),
+                "{}",
+                path
+            );
+        }
+        // The exclusion is dropped: its paths go to the next matching rule
+        assert_eq!(
+            pattern_of(&original, "src/gen/a.rs").as_deref(),
+            Some("src/**")
+        );
+        assert_eq!(
+            pattern_of(&imported, "src/gen/a.rs").as_deref(),
+            Some("**/gen/**")
+        );
+    }
+
+    #[cfg(feature = "links")]
+    #[test]
+    fn check_link_accepts_urls_and_repo_paths() {
+        for value in [
+            "https://wiki.acme.dev/payments",
+            "http://localhost:8080/runbook",
+            "docs/payments.md",
+            "./runbooks/api.md",
+            "../shared/README.md",
+        ] {
+            assert_eq!(check_link(value), None, "{}", value);
+        }
+    }
+
+    #[cfg(feature = "links")]
+    #[test]
+    fn check_link_flags_broken_values() {
+        let cases = [
+            ("", "is empty"),
+            ("docs/my runbook.md", "contains whitespace"),
+            ("ftp://files.acme.dev/x", "unexpected scheme 'ftp'"),
+            ("https://intranet/x", "has no valid host"),
+            ("https://[::1/x", "is not a valid URL"),
+            ("wiki.acme.dev/payments", "without a scheme"),
+            ("www.acme.dev", "without a scheme"),
+        ];
+        for (value, expected) in cases {
+            let problem = check_link(value).unwrap_or_default();
+            assert!(problem.contains(expected), "{:?}: {:?}", value, problem);
+        }
+    }
+
+    #[test]
+    fn split_inline_owners_finds_trailing_owners() {
+        assert_eq!(
+            split_inline_owners("payments @acme/payments  @alice"),
+            ("payments", vec!["@acme/payments", "@alice"])
+        );
+        let m = mapper("src/pay/** payments @acme/payments\n");
+        assert_eq!(
+            m.rule_owners(0)
+                .iter()
+                .map(Owner::to_string)
+                .collect::<Vec<_>>(),
+            ["@acme/payments"]
+        );
+        assert_eq!(m.find_service("src/pay/x.rs"), Some("payments"));
+    }
+
+    #[test]
+    fn split_inline_owners_without_an_at_token() {
+        assert_eq!(split_inline_owners("payments"), ("payments", Vec::new()));
+        assert_eq!(
+            split_inline_owners("pay@ments alice@acme.dev"),
+            ("pay@ments alice@acme.dev", Vec::new())
+        );
+        assert!(mapper("src/** core\n").rule_owners(0).is_empty());
+    }
+
+    #[cfg(feature = "ignore-file")]
+    #[test]
+    fn ignore_rules_match_like_gitignore() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(".serviceownersignore");
+        fs::write(
+            &path,
+            "# generated\n*.lock\n/vendor/\ngen/**\n!gen/keep.rs\n",
+        )
+        .unwrap();
+        let rules = IgnoreRules::from_file(&path).unwrap();
+        assert!(!rules.is_empty());
+        for ignored in ["Cargo.lock", "web/yarn.lock", "vendor/x/y.go", "gen/a.rs"] {
+            assert!(rules.is_ignored(ignored), "{}", ignored);
+        }
+        for kept in ["src/lib.rs", "web/vendor/y.go", "gen/keep.rs", "lock"] {
+            assert!(!rules.is_ignored(kept), "{}", kept);
+        }
+    }
+
+    #[cfg(feature = "ignore-file")]
+    #[test]
+    fn missing_ignore_file_ignores_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let rules = IgnoreRules::from_file(&dir.path().join("absent")).unwrap();
+        assert!(rules.is_empty());
+        assert!(!rules.is_ignored("Cargo.lock"));
+        assert!(!IgnoreRules::empty().is_ignored("anything"));
+    }
+
+    #[test]
+    fn near_misses_rank_by_shared_prefix() {
+        let m = mapper(
+            "src/auth/** auth\nsrc/api/** api\nsrc/api/v1/** v1\n**/*.md docs\nweb/** web\n",
+        );
+        let misses = m.near_misses("src/api/v2/new.rs", 5);
+        let found: Vec<(&str, usize)> = misses
+            .iter()
+            .map(|miss| (miss.pattern, miss.shared_segments))
+            .collect();
+        assert_eq!(found, [("src/api/v1/**", 2), ("src/auth/**", 1)]);
+        assert_eq!((misses[0].service, misses[0].line), ("v1", 3));
+        assert_eq!(m.near_misses("src/api/v2/new.rs", 1).len(), 1);
+    }
+
+    #[test]
+    fn near_misses_skip_matches_and_wildcards() {
+        let m = mapper("src/** core\n**/*.md docs\n*.lock deps\n");
+        assert!(m.near_misses("src/x.rs", 5).is_empty());
+        assert!(m.near_misses("tools/README.txt", 5).is_empty());
+    }
+
+    #[test]
+    fn owner_render_in_each_format() {
+        let team = Owner::Team {
+            team: "@acme/payments".to_string(),
+            slack: Some("S0123".to_string()),
+        };
+        let user = Owner::User {
+            user: "alice".to_string(),
+            slack: Some("U0456".to_string()),
+        };
+        assert_eq!(team.render(OwnerFormat::Mention), "@acme/payments");
+        assert_eq!(team.render(OwnerFormat::Plain), "acme/payments");
+        assert_eq!(team.render(OwnerFormat::Slack), "<!subteam^S0123>");
+        assert_eq!(user.render(OwnerFormat::Mention), "@alice");
+        assert_eq!(user.render(OwnerFormat::Slack), "<@U0456>");
+    }
+
+    #[test]
+    fn owner_render_falls_back_without_a_slack_id() {
+        let team = Owner::Team {
+            team: "@acme/payments".to_string(),
+            slack: None,
+        };
+        let email = Owner::Email {
+            email: "pay@acme.dev".to_string(),
+        };
+        assert_eq!(team.render(OwnerFormat::Slack), "acme/payments");
+        assert_eq!(email.render(OwnerFormat::Mention), "pay@acme.dev");
+        assert_eq!(email.render(OwnerFormat::Slack), "pay@acme.dev");
+        assert_eq!(
+            Owner::Raw("bob".to_string()).render(OwnerFormat::Mention),
+            "@bob"
+        );
+    }
+
+    #[test]
+    fn services_parse_errors_point_at_line_and_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.yaml");
+        fs::write(&path, "services:\n  api:\n    owners: 42\n").unwrap();
+        let err = ServicesFile::from_file(&path).unwrap_err();
+        let expected = format!("Failed to parse services file at {}:3:", path.display());
+        assert!(err.to_string().starts_with(&expected), "{}", err);
+    }
+
+    #[test]
+    fn services_errors_after_parsing_have_no_location() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("services.yaml");
+        fs::write(&path, "services:\n  api:\n    owners: [\"@acme/api\"]\n").unwrap();
+        assert!(ServicesFile::from_file(&path).is_ok());
+        fs::write(&path, "version: 99\nservices: {}\n").unwrap();
+        let err = ServicesFile::from_file(&path).unwrap_err().to_string();
+        assert!(
+            err.starts_with("Unsupported services file version 99"),
+            "{}",
+            err
+        );
+        assert!(!err.contains(":1:"), "{}", err);
+    }
+
+    #[test]
+    fn stop_words_are_skipped_when_inferring_names() {
+        let opts = InitOptions {
+            stop_words: vec!["modules".to_string()],
+            ..InitOptions::default()
+        };
+        assert_eq!(infer_service_name("/src/Billing/", &[], &opts), "billing");
+        assert_eq!(
+            infer_service_name("/apps/web/modules/", &["@acme/web"], &opts),
+            "web"
+        );
+        let keep = InitOptions {
+            keep_case: true,
+            ..opts
+        };
+        assert_eq!(infer_service_name("/src/Billing/", &[], &keep), "Billing");
+    }
+
+    #[test]
+    fn only_stop_words_fall_back_to_the_owner() {
+        let opts = InitOptions::default();
+        assert_eq!(
+            infer_service_name("/src/lib/", &["@acme/core-team"], &opts),
+            "core_team"
+        );
+        assert_eq!(infer_service_name("/modules/", &[], &opts), "modules");
+        assert_eq!(infer_service_name("/src/", &[], &opts), "unknown_service");
+    }
 }
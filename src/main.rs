@@ -1,9 +1,25 @@
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
-use serviceowners::{init_from_codeowners, ServiceMapper};
-use std::collections::{HashMap, HashSet};
+use clap::{Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
+use serviceowners::{
+    check_link, export_codeowners, init_from_codeowners_with, migrate_services_yaml,
+    normalize_query_path, CommitImpact, ContactRow, Diagnostic, ExplainDiffRow, ExplainMatch,
+    FileStatus, IgnoreRules, ImpactReport, InitOptions, LintResult, MatchStrategy, MoveAcross,
+    MoveWithin, NearMissRow, Owner, OwnerFormat, ParseOptions, PatternCounts, PatternTester,
+    RemovedRule, RuleMatch, RuleRef, RuleUsage, ServiceDef, ServiceImpact, ServiceMapper,
+    ServiceRules, ServicesFile, ServicesOptions, Severity, StatsReport, TeamBreakdown, TeamImpact,
+    UnmappedGroup, WhoOwnsReport, WhoOwnsRow, WhyUnmappedReport, JSON_SCHEMA_VERSION,
+    SERVICES_SCHEMA_VERSION,
+};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "sowners")]
@@ -13,59 +29,496 @@ struct Cli {
     command: Commands,
 
     /// Path to SERVICEOWNERS file
-    #[arg(long, default_value = "SERVICEOWNERS")]
+    #[arg(long, global = true, default_value = "SERVICEOWNERS")]
     serviceowners_file: PathBuf,
 
-    /// Path to services.yaml file
-    #[arg(long, default_value = "services.yaml")]
+    /// SERVICEOWNERS rules given inline (e.g. 'src/** backend') instead of read from a file
+    #[arg(long, global = true, conflicts_with = "serviceowners_file")]
+    serviceowners_content: Option<String>,
+
+    /// Path to services.yaml file, or a directory of *.yaml files to merge
+    #[arg(long, global = true, default_value = "services.yaml")]
     services_file: PathBuf,
+
+    /// Keep `${VAR}` in services.yaml values literal instead of expanding from the environment
+    #[arg(long, global = true)]
+    no_expand: bool,
+
+    /// Let `*` cross directory separators like CODEOWNERS (`docs/*` matches `docs/a/b.md`)
+    #[arg(long, global = true)]
+    codeowners_compat: bool,
+
+    /// Only consider paths under this directory (e.g. `services/`); others are unmapped
+    #[arg(long, global = true)]
+    scope: Option<String>,
+
+    /// Delimiter between pattern and service in SERVICEOWNERS (default: whitespace)
+    #[arg(long, global = true)]
+    separator: Option<String>,
+
+    /// Gitignore-style file of paths that are intentionally unowned
+    #[arg(long, global = true, default_value = ".sownersignore")]
+    ignore_file: PathBuf,
+
+    /// Log matching decisions to stderr (-v: info, -vv: debug, -vvv: trace)
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print JSON output on a single line instead of pretty-printed
+    #[arg(long, global = true)]
+    compact: bool,
+
+    /// Timeout in seconds for each HTTP request (GitHub API, link checks)
+    #[arg(long, global = true, default_value_t = 10)]
+    http_timeout: u64,
+
+    /// Maximum number of HTTP requests in flight at once
+    #[arg(long, global = true, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+    concurrency: u64,
+
+    /// Exit 6 if the command printed any warning
+    #[arg(long, global = true)]
+    fail_on_warnings: bool,
+
+    /// Count submodule pointer updates in diffs as changed files
+    #[arg(long, global = true)]
+    include_submodules: bool,
+
+    /// Count files deleted in diffs as changed files
+    #[arg(long, global = true)]
+    include_deleted: bool,
+
+    /// Attribute paths no rule matches to this service instead of leaving them unmapped
+    #[arg(long, global = true, value_name = "SERVICE")]
+    default_service: Option<String>,
+
+    /// How owners are printed (default: as written; mentions in GitHub Markdown and PR comments)
+    #[arg(long, global = true, value_enum)]
+    owners_format: Option<OwnerFormat>,
+}
+
+/// Warnings printed so far, for `--fail-on-warnings`
+static WARNINGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a warning toward `--fail-on-warnings`; call wherever a `Warning:`
+/// (or `::warning::`) line is printed.
+fn note_warning() {
+    WARNINGS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// `--owners-format`, when given
+static OWNERS_FORMAT: OnceLock<OwnerFormat> = OnceLock::new();
+
+/// An owner for text and Markdown output: as written unless `--owners-format` is set.
+fn owner_label(owner: &Owner) -> String {
+    match OWNERS_FORMAT.get() {
+        Some(format) => owner.render(*format),
+        None => owner.to_string(),
+    }
+}
+
+/// An owner where a mention notifies them (GitHub Markdown, PR comments).
+fn owner_mention(owner: &Owner) -> String {
+    owner.render(OWNERS_FORMAT.get().copied().unwrap_or(OwnerFormat::Mention))
+}
+
+/// Limits applied to every outbound HTTP interaction
+#[derive(Clone, Copy)]
+struct HttpOptions {
+    timeout: Duration,
+    concurrency: usize,
+}
+
+impl HttpOptions {
+    fn client(&self) -> Result<reqwest::blocking::Client> {
+        reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .user_agent("serviceowners-rust")
+            .build()
+            .context("Failed to build HTTP client")
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ImpactedFormat {
+    Text,
+    Json,
+    Markdown,
+    /// Markdown with @-mentions for owner teams and docs/runbook links
+    GithubMarkdown,
+    /// Tab-separated `service`, `file_count`, `file` rows, one per changed file
+    Tsv,
+    /// "Services changed in ..." list for release notes
+    Changelog,
+}
+
+/// What `impacted` groups changed files under
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ImpactGroup {
+    Service,
+    /// The `team:` owners of each service in services.yaml
+    Team,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum TextOrJson {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WhoOwnsFormat {
+    Text,
+    Json,
+    /// `path  service` in aligned columns for reading in a terminal
+    PathService,
+}
+
+/// Code host the Action's PR comment is posted to
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Platform {
+    /// Bitbucket when `BITBUCKET_PR_ID` is set, otherwise GitHub
+    Auto,
+    Github,
+    /// Bitbucket Server / Data Center
+    Bitbucket,
+}
+
+/// Commands whose JSON output has a published schema
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SchemaKind {
+    Impacted,
+    Lint,
+    /// `who-owns PATH`; a list of paths gives `{path, service}` rows
+    WhoOwns,
+    WhyUnmapped,
+    ExplainDiff,
+    Contacts,
+    Stats,
+    /// The services.yaml file itself, for editor completion and validation
+    Services,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ReportFormat {
+    Html,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ContactsFormat {
+    Table,
+    Json,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Find out who owns a specific path
     WhoOwns {
-        path: String,
+        /// Paths to look up, or `-` to read one path per line from stdin
+        #[arg(required_unless_present = "files_from")]
+        paths: Vec<String>,
+        /// Also look up the paths listed in this file (one per line; `-` for stdin)
+        #[arg(long, value_name = "FILE")]
+        files_from: Option<String>,
         #[arg(long)]
         explain: bool,
+        /// With --explain, show each matching rule's specificity score
+        #[arg(long, requires = "explain")]
+        specificity: bool,
+        /// For unmapped paths, suggest the owner of the nearest ancestor
+        #[arg(long)]
+        nearest: bool,
+        /// Print how many rules matched the path, not just the chosen one
+        #[arg(long)]
+        count_matches: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = WhoOwnsFormat::Text)]
+        format: WhoOwnsFormat,
+        /// One stable line per path: `OK <service> <path>` or `?? <path>`
+        #[arg(long, conflicts_with = "format")]
+        porcelain: bool,
+        /// With several paths, only look up those matching this glob (repeatable; any may match)
+        #[arg(long, value_name = "GLOB")]
+        path_filter: Vec<String>,
+        /// Which matching rule decides a path: last (CODEOWNERS), first, or most specific
+        #[arg(long = "match", value_enum, default_value_t = MatchStrategy::LastWins)]
+        match_strategy: MatchStrategy,
     },
     /// List services impacted by changes
     Impacted {
-        /// Git diff range (e.g. origin/main...HEAD)
+        /// Git diff range (e.g. origin/main...HEAD); repeat to union several ranges
         #[arg(long)]
-        diff: Option<String>,
+        diff: Vec<String>,
+
+        /// Start of a release range (tag, branch or sha); diffs `FROM...TO`
+        #[arg(long, conflicts_with = "diff")]
+        from: Option<String>,
+
+        /// End of the release range (default: HEAD)
+        #[arg(long, requires = "from")]
+        to: Option<String>,
 
         /// Fail if unmapped files are found
         #[arg(long)]
         fail_on_unmapped: bool,
 
-        /// Output format (text, json, markdown)
-        #[arg(long, default_value = "text")]
-        format: String,
+        /// Fail only if more than N files are unmapped (new ones, with --baseline)
+        #[arg(long, value_name = "N", conflicts_with = "fail_on_unmapped")]
+        fail_on_unmapped_threshold: Option<usize>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ImpactedFormat::Text)]
+        format: ImpactedFormat,
 
         /// List changed files per service
         #[arg(long)]
         show_files: bool,
+
+        /// Group unmapped files by top-level directory with counts
+        #[arg(long)]
+        group_unmapped: bool,
+
+        /// Include owners and contact info from services.yaml
+        #[arg(long)]
+        owners: bool,
+
+        /// File listing known unmapped paths; only paths not in it fail --fail-on-unmapped
+        #[arg(long)]
+        baseline: Option<PathBuf>,
+
+        /// Write this run's unmapped files to --baseline instead of gating
+        #[arg(long, requires = "baseline")]
+        write_baseline: bool,
+
+        /// Exit 4 if a deprecated service is impacted
+        #[arg(long)]
+        fail_on_deprecated: bool,
+
+        /// JSON: list every known service with an `impacted` flag, not just impacted ones
+        #[arg(long)]
+        include_unchanged_owners: bool,
+
+        /// Exit 5 if an impacted service has no owners (in services.yaml or inline)
+        #[arg(long)]
+        fail_on_ownerless: bool,
+
+        /// Text/Markdown: beyond N services, show only the top N by file count
+        /// (JSON and TSV always list everything)
+        #[arg(long)]
+        max_services: Option<usize>,
+
+        /// TSV: omit the header row
+        #[arg(long)]
+        no_header: bool,
+
+        /// Use uncommitted changes (staged, unstaged and untracked) instead of a diff range
+        #[arg(long, conflicts_with_all = ["diff", "from", "commits"])]
+        against_working_tree: bool,
+
+        /// Break the impact down per commit in the range (oldest first)
+        #[arg(long, conflicts_with_all = ["baseline", "include_unchanged_owners"])]
+        commits: bool,
+
+        /// Track renames: moves within one service collapse into a note, moves
+        /// across services count against both
+        #[arg(long, conflicts_with_all = ["against_working_tree", "commits"])]
+        detect_renames: bool,
+
+        /// Treat every file in the repo as changed: a full ownership inventory
+        #[arg(
+            long,
+            conflicts_with_all = ["diff", "from", "against_working_tree", "commits", "detect_renames"]
+        )]
+        all_files: bool,
+
+        /// Leave out files whose changes are whitespace only (git diff -w)
+        #[arg(long, conflicts_with_all = ["against_working_tree", "all_files", "commits"])]
+        ignore_whitespace: bool,
+
+        /// Only analyze changed files matching this glob (repeatable; any may match)
+        #[arg(long, value_name = "GLOB", conflicts_with = "commits")]
+        path_filter: Vec<String>,
+
+        /// Read changed paths from this file (one per line; `-` for stdin) instead
+        /// of running git diff. --diff and --from are ignored.
+        #[arg(
+            long,
+            value_name = "FILE",
+            conflicts_with_all = [
+                "against_working_tree",
+                "commits",
+                "detect_renames",
+                "all_files",
+                "ignore_whitespace"
+            ]
+        )]
+        files_from: Option<String>,
+
+        /// Group the report by service or by owning team (text, json and markdown)
+        #[arg(long, value_enum, default_value_t = ImpactGroup::Service, conflicts_with = "commits")]
+        group_by: ImpactGroup,
+
+        /// Which matching rule decides a path: last (CODEOWNERS), first, or most specific
+        #[arg(long = "match", value_enum, default_value_t = MatchStrategy::LastWins)]
+        match_strategy: MatchStrategy,
+    },
+    /// Explain why each changed file resolved to its service
+    ExplainDiff {
+        /// Git diff range (e.g. origin/main...HEAD)
+        #[arg(long)]
+        diff: Option<String>,
+
+        /// Output format
+        #[arg(long, value_enum, default_value_t = TextOrJson::Text)]
+        format: TextOrJson,
+
+        /// Which matching rule decides a path: last (CODEOWNERS), first, or most specific
+        #[arg(long = "match", value_enum, default_value_t = MatchStrategy::LastWins)]
+        match_strategy: MatchStrategy,
     },
     /// Lint the SERVICEOWNERS file
     Lint {
-        /// Strict mode (currently unused, for compat)
+        /// Strict mode: conflicting rules and undefined services are errors (exit 2)
         #[arg(long)]
         strict: bool,
+        /// Also report services declared in services.yaml that no rule maps to
+        #[arg(long)]
+        unused_services: bool,
         /// Check if patterns match any files
         #[arg(long)]
         check_matches: bool,
+        /// Suppress the progress indicator
+        #[arg(long)]
+        quiet: bool,
+        /// Leave binary files (images, archives, ...) out of the walk
+        #[arg(long)]
+        skip_binary: bool,
+        /// Send a HEAD request to every docs/runbook URL to verify it resolves
+        #[arg(long)]
+        check_links: bool,
+        /// Rewrite SERVICEOWNERS without the patterns --check-matches found unused
+        #[arg(long, requires = "check_matches", conflicts_with = "skip_binary")]
+        fix: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = TextOrJson::Text)]
+        format: TextOrJson,
+    },
+    /// Repo-wide ownership report (services, owners, rule and file counts)
+    Report {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ReportFormat::Html)]
+        format: ReportFormat,
+        /// Suppress the progress indicator
+        #[arg(long)]
+        quiet: bool,
+        /// Leave binary files (images, archives, ...) out of the file counts
+        #[arg(long)]
+        skip_binary: bool,
+    },
+    /// Explain why a path is unmapped: the rules that nearly matched and a rule to add
+    WhyUnmapped {
+        path: String,
+        /// How many near-miss rules to list
+        #[arg(long, default_value_t = 5)]
+        limit: usize,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = TextOrJson::Text)]
+        format: TextOrJson,
+    },
+    /// Dry-run a pattern against paths without editing SERVICEOWNERS
+    PatternTest {
+        /// Pattern as it would appear in SERVICEOWNERS
+        pattern: String,
+        /// Paths to test
+        paths: Vec<String>,
+        /// Test against every file in the repo instead
+        #[arg(long, conflicts_with = "paths")]
+        walk: bool,
+        /// Suppress the progress indicator
+        #[arg(long)]
+        quiet: bool,
+    },
+    /// Summarize rule health (rule and service counts, pattern kinds, specificity)
+    Stats {
+        /// Walk the repo to find the most-used and unused rules
+        #[arg(long)]
+        check_matches: bool,
+        /// Suppress the progress indicator
+        #[arg(long)]
+        quiet: bool,
+        /// Leave binary files (images, archives, ...) out of the walk
+        #[arg(long)]
+        skip_binary: bool,
+        /// Output format
+        #[arg(long, value_enum, default_value_t = TextOrJson::Text)]
+        format: TextOrJson,
+    },
+    /// List every service's Slack, email, docs and runbook for on-call routing
+    Contacts {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = ContactsFormat::Table)]
+        format: ContactsFormat,
+    },
+    /// Report unknown keys and malformed values in services.yaml
+    ValidateServices {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = TextOrJson::Text)]
+        format: TextOrJson,
+    },
+    /// Print the JSON Schema of a command's `--format json` output, or of services.yaml
+    Schema {
+        #[arg(value_enum)]
+        command: SchemaKind,
+    },
+    /// Check the setup (git, SERVICEOWNERS, services.yaml, diff range) and suggest fixes
+    Doctor {
+        /// Diff range to check (default: the one `impacted` would use)
+        #[arg(long)]
+        diff: Option<String>,
+    },
+    /// Upgrade services.yaml to the current schema version, keeping comments
+    MigrateServices {
+        /// Rewrite the file in place instead of printing the result
+        #[arg(long)]
+        write: bool,
     },
     /// Initialize from CODEOWNERS
     Init {
         #[arg(long)]
         codeowners: Option<PathBuf>,
+        /// Write to --serviceowners-file instead of printing
+        #[arg(long)]
+        write: bool,
+        /// Write to this path instead of printing (e.g. to review before moving it into place)
+        #[arg(long, conflicts_with = "write")]
+        output: Option<PathBuf>,
+        /// Overwrite the output file if it already exists
+        #[arg(long)]
+        force: bool,
+        /// Group rules under `# === service ===` headers, sorted by service
+        #[arg(long)]
+        group_by_service: bool,
+        /// Extra directory names to skip when naming services (comma-separated),
+        /// added to the defaults: src, lib, packages, apps
+        #[arg(long, value_delimiter = ',')]
+        stop_words: Vec<String>,
+        /// Don't lowercase inferred service names
+        #[arg(long)]
+        keep_case: bool,
+    },
+    /// Generate a CODEOWNERS file from SERVICEOWNERS and services.yaml owners
+    ExportCodeowners {
+        /// Where --write puts the file
+        #[arg(long, default_value = ".github/CODEOWNERS")]
+        codeowners: PathBuf,
+        /// Write to --codeowners instead of printing
         #[arg(long)]
         write: bool,
+        /// Overwrite the output file if it already exists
         #[arg(long)]
         force: bool,
     },
-    /// Run as a GitHub Action
+    /// Run as a GitHub Action (or post to Bitbucket with --platform)
     Action {
         #[arg(long)]
         diff: Option<String>,
@@ -75,109 +528,629 @@ enum Commands {
         fail_on_unmapped: String,
         #[arg(long, default_value = "false")]
         strict_lint: String,
+        /// Handlebars template for the PR comment (defaults to the built-in report)
+        #[arg(long)]
+        template: Option<PathBuf>,
+        /// Don't post a comment when nothing is impacted or unmapped
+        #[arg(long, default_value = "true")]
+        skip_empty: String,
+        /// Exit 4 if a deprecated service is impacted
+        #[arg(long, default_value = "false")]
+        fail_on_deprecated: String,
+        /// Diff range whose services count as already impacted (e.g. the
+        /// base of a stacked PR); others are marked as new in the comment
+        #[arg(long, conflicts_with = "baseline_services")]
+        baseline_diff: Option<String>,
+        /// Comma-separated services that count as already impacted
+        #[arg(long)]
+        baseline_services: Option<String>,
+        /// Where to post the PR comment
+        #[arg(long, value_enum, default_value_t = Platform::Auto)]
+        platform: Platform,
+        /// Markdown appended to the comment, e.g. a link to SERVICEOWNERS;
+        /// `{repo}` and `{commit}` are filled in
+        #[arg(long)]
+        comment_footer: Option<String>,
     },
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
-
     let cli = Cli::parse();
+    let fail_on_warnings = cli.fail_on_warnings;
+    if let Some(format) = cli.owners_format {
+        let _ = OWNERS_FORMAT.set(format);
+    }
+    run(cli)?;
+    let warnings = WARNINGS.load(Ordering::Relaxed);
+    if fail_on_warnings && warnings > 0 {
+        eprintln!("{} warning(s) with --fail-on-warnings set", warnings);
+        std::process::exit(6);
+    }
+    Ok(())
+}
+
+fn run(cli: Cli) -> Result<()> {
+    // -v only raises our own logs; RUST_LOG still controls dependencies
+    let mut logger = env_logger::Builder::from_default_env();
+    match cli.verbose {
+        0 => {}
+        1 => {
+            logger.filter_module("serviceowners", log::LevelFilter::Info);
+        }
+        2 => {
+            logger.filter_module("serviceowners", log::LevelFilter::Debug);
+        }
+        _ => {
+            logger.filter_module("serviceowners", log::LevelFilter::Trace);
+        }
+    }
+    logger.init();
+
+    let parse_opts = ParseOptions {
+        codeowners_compat: cli.codeowners_compat,
+        root_scope: cli.scope.clone(),
+        separator: cli.separator.clone(),
+    };
+    let services_opts = ServicesOptions {
+        expand_env: !cli.no_expand,
+    };
+    let diff_opts = DiffOptions {
+        include_submodules: cli.include_submodules,
+        include_deleted: cli.include_deleted,
+        ignore_whitespace: false,
+    };
+    // Where the rules come from, for messages
+    let rules_name = match &cli.serviceowners_content {
+        Some(_) => "--serviceowners-content".to_string(),
+        None => format!("{:?}", cli.serviceowners_file),
+    };
+    let read_rules = || match &cli.serviceowners_content {
+        Some(content) => Ok(content.clone()),
+        None => std::fs::read_to_string(&cli.serviceowners_file).with_context(|| {
+            format!(
+                "Failed to read SERVICEOWNERS file at {:?}",
+                cli.serviceowners_file
+            )
+        }),
+    };
+    let parse_mapper = || match &cli.serviceowners_content {
+        Some(content) => ServiceMapper::parse_with(content, &parse_opts),
+        None => ServiceMapper::from_file_with(&cli.serviceowners_file, &parse_opts),
+    };
+    let load_mapper = || {
+        let mut mapper = parse_mapper()?;
+        if let Some(service) = &cli.default_service {
+            mapper = mapper.with_default_service(service.clone());
+        }
+        if mapper.is_empty() {
+            note_warning();
+            eprintln!(
+                "Warning: {} contains no rules; every file will be unmapped.",
+                rules_name
+            );
+        }
+        Ok::<_, anyhow::Error>(mapper)
+    };
+    let load_ignore = || IgnoreRules::from_file(&cli.ignore_file);
+    let http = HttpOptions {
+        timeout: Duration::from_secs(cli.http_timeout),
+        concurrency: cli.concurrency as usize,
+    };
+
+    let compact = cli.compact;
 
     match cli.command {
-        Commands::WhoOwns { path, explain } => {
-            let mapper = ServiceMapper::from_file(&cli.serviceowners_file)?;
+        Commands::WhoOwns {
+            mut paths,
+            files_from,
+            explain,
+            specificity,
+            nearest,
+            count_matches,
+            format,
+            porcelain,
+            path_filter,
+            match_strategy,
+        } => {
+            let mapper = load_mapper()?.with_match_strategy(match_strategy);
+            let stdin = paths == ["-"] && files_from.is_none();
+            if paths.len() > 1 && paths.iter().any(|p| p == "-") {
+                anyhow::bail!("`-` reads paths from stdin and can't be mixed with other paths");
+            }
+            if files_from.is_some() && paths.iter().any(|p| p == "-") {
+                anyhow::bail!(
+                    "`-` reads paths from stdin and can't be combined with --files-from (use --files-from -)"
+                );
+            }
+            let list = stdin || paths.len() > 1 || files_from.is_some();
+            if !path_filter.is_empty() && !list {
+                anyhow::bail!(
+                    "--path-filter filters a list of paths; pass several paths, `-` or --files-from"
+                );
+            }
+            let path_filter = PathFilter::new(&path_filter, &parse_opts)?;
+            let batch = list || porcelain || format == WhoOwnsFormat::PathService;
+            if batch {
+                if explain || nearest || count_matches {
+                    anyhow::bail!(
+                        "--explain, --nearest and --count-matches take a single path, not a list, --porcelain or --format path-service"
+                    );
+                }
+                if stdin {
+                    paths = read_stdin_paths()?;
+                } else if let Some(source) = &files_from {
+                    paths.extend(read_paths_from(source)?);
+                }
+                if list {
+                    paths.retain(|p| path_filter.allows(p));
+                }
+                let services = load_services(&cli.services_file, &services_opts)?;
+                let owned: Vec<(String, Option<&str>)> = paths
+                    .into_iter()
+                    .map(|p| {
+                        let svc = mapper
+                            .find_service(&p)
+                            .map(|s| canonical(services.as_ref(), s));
+                        (p, svc)
+                    })
+                    .collect();
+                if format == WhoOwnsFormat::Json {
+                    let rows: Vec<WhoOwnsRow> = owned
+                        .iter()
+                        .map(|(p, svc)| WhoOwnsRow {
+                            path: p.clone(),
+                            service: svc.map(str::to_string),
+                        })
+                        .collect();
+                    print_json(&rows, compact)?;
+                    return Ok(());
+                }
+                if format == WhoOwnsFormat::PathService && std::io::stdout().is_terminal() {
+                    let width = owned.iter().map(|(p, _)| p.chars().count()).max();
+                    for (p, svc) in &owned {
+                        let svc = svc.unwrap_or("(unmapped)");
+                        println!("{:<w$}  {}", p, svc, w = width.unwrap_or(0));
+                    }
+                    return Ok(());
+                }
+                for (p, svc) in &owned {
+                    match (porcelain, svc) {
+                        (true, Some(svc)) => println!("OK {} {}", svc, p),
+                        (true, None) => println!("?? {}", p),
+                        (false, svc) if stdin => println!("{}\t{}", p, svc.unwrap_or_default()),
+                        (false, svc) => println!("{}: {}", p, svc.unwrap_or("(unmapped)")),
+                    }
+                }
+                return Ok(());
+            }
+            let path = paths.swap_remove(0);
+            if format == WhoOwnsFormat::Json {
+                let services = load_services(&cli.services_file, &services_opts)?;
+                let svc = mapper.find_service(&path);
+                let mut payload = WhoOwnsReport {
+                    schema_version: JSON_SCHEMA_VERSION,
+                    path: path.clone(),
+                    service: svc.map(|s| canonical(services.as_ref(), s).to_string()),
+                    owners: None,
+                    contact: None,
+                    docs: None,
+                    runbook: None,
+                    matches: None,
+                    match_count: None,
+                    nearest: None,
+                };
+                if let Some(def) = svc.and_then(|s| services.as_ref()?.get(s)) {
+                    payload.owners =
+                        Some(def.owners.iter().flatten().map(|o| o.to_string()).collect());
+                    payload.contact = def.contact.clone();
+                    payload.docs = def.docs.clone();
+                    payload.runbook = def.runbook.clone();
+                } else if let Some(owners) = mapper.owners_for_path(&path) {
+                    payload.owners = Some(owners.iter().map(|o| o.to_string()).collect());
+                }
+                let matches = mapper.explain_service(&path);
+                if explain {
+                    payload.matches = Some(
+                        matches
+                            .iter()
+                            .map(|m| RuleMatch::new(m, specificity))
+                            .collect(),
+                    );
+                }
+                if count_matches {
+                    payload.match_count = Some(matches.iter().filter(|m| !m.fallback).count());
+                }
+                if svc.is_none() && nearest {
+                    payload.nearest = mapper.nearest_service(&path).as_ref().map(RuleRef::from);
+                }
+                print_json(&payload, compact)?;
+                return Ok(());
+            }
             match mapper.find_service(&path) {
                 Some(svc) => {
-                    println!("{}", svc);
-                    if explain {
-                        println!("\nMatches:");
-                        let matches = mapper.explain_service(&path);
-                        for m in matches {
-                            let chosen = if m.service == svc { " <== chosen" } else { "" };
-                            println!("- {} -> {}{}", m.pattern, m.service, chosen);
+                    let services = load_services(&cli.services_file, &services_opts)?;
+                    println!("{}", canonical(services.as_ref(), svc));
+                    if let Some(def) = services.as_ref().and_then(|s| s.get(svc)) {
+                        print_service_details(def, "");
+                        for (field, link) in def.links() {
+                            println!("{}{}: {}", field[..1].to_uppercase(), &field[1..], link);
                         }
+                    } else if let Some(owners) = mapper.owners_for_path(&path) {
+                        let names: Vec<String> = owners.iter().map(owner_label).collect();
+                        println!("Owners: {}", names.join(", "));
+                    } else if services.is_some() {
+                        eprintln!(
+                            "Note: '{}' is not defined in {}; no owners or contacts to show.",
+                            svc,
+                            cli.services_file.display()
+                        );
+                    }
+                    if explain {
+                        print_explain_matches(&mapper, &path, specificity);
                     }
                 }
                 None => {
-                    println!("Unmapped");
+                    match mapper.nearest_service(&path).filter(|_| nearest) {
+                        Some(m) => println!("Unmapped (nearest: {} via {})", m.service, m.pattern),
+                        None => println!("Unmapped"),
+                    }
                     if explain {
-                        println!("\nNo matches found.");
+                        print_explain_matches(&mapper, &path, specificity);
                     }
                 }
             }
+            if count_matches {
+                let rules = mapper.explain_service(&path);
+                println!(
+                    "Matching rules: {}",
+                    rules.iter().filter(|m| !m.fallback).count()
+                );
+            }
         }
         Commands::Impacted {
-            diff,
+            mut diff,
+            mut from,
+            to,
             fail_on_unmapped,
+            fail_on_unmapped_threshold,
             format,
             show_files,
+            group_unmapped,
+            owners,
+            baseline,
+            write_baseline,
+            fail_on_deprecated,
+            include_unchanged_owners,
+            fail_on_ownerless,
+            max_services,
+            no_header,
+            against_working_tree,
+            commits,
+            detect_renames,
+            all_files,
+            ignore_whitespace,
+            path_filter,
+            files_from,
+            group_by,
+            match_strategy,
         } => {
-            let mapper = ServiceMapper::from_file(&cli.serviceowners_file)?;
+            let by_team = group_by == ImpactGroup::Team;
+            if by_team
+                && !matches!(
+                    format,
+                    ImpactedFormat::Text | ImpactedFormat::Json | ImpactedFormat::Markdown
+                )
+            {
+                anyhow::bail!("--group-by team works with text, json and markdown output");
+            }
+            if by_team && max_services.is_some() {
+                anyhow::bail!(
+                    "--max-services limits services, so it can't be used with --group-by team"
+                );
+            }
+            let path_filter = PathFilter::new(&path_filter, &parse_opts)?;
+            if files_from.is_some() && (!diff.is_empty() || from.is_some()) {
+                eprintln!("Warning: --files-from is set, so --diff/--from are ignored");
+                note_warning();
+                diff.clear();
+                from = None;
+            }
+            let mapper = load_mapper()?.with_match_strategy(match_strategy);
+            let services = load_services_with_inline(&cli.services_file, &services_opts, &mapper)?;
+            let mut release = None;
+            if let Some(from) = &from {
+                let to = to.as_deref().unwrap_or("HEAD");
+                verify_commit("--from", from)?;
+                verify_commit("--to", to)?;
+                diff.push(format!("{}...{}", from, to));
+                release = Some(format!("{} (since {})", to, from));
+            }
+            if commits {
+                if diff.len() > 1 {
+                    anyhow::bail!("--commits takes a single --diff range");
+                }
+                let ignore = load_ignore()?;
+                let services_of = |sha: &str| -> Result<Vec<String>> {
+                    let files = commit_changed_files(sha, diff_opts)?;
+                    let impact = classify_files(&mapper, services.as_ref(), &ignore, &files);
+                    Ok(impact.impacted_services)
+                };
+                return impacted_by_commit(
+                    diff.first().map(String::as_str),
+                    services_of,
+                    format,
+                    compact,
+                    no_header,
+                );
+            }
+            let service_def = |svc: &str| services.as_ref().and_then(|s| s.get(svc));
+            let owner_def = |svc: &str| if owners { service_def(svc) } else { None };
 
-            let files = get_changed_files(diff.as_deref())?;
-            let mut service_files: HashMap<String, Vec<String>> = HashMap::new();
-            let mut unmapped_files = Vec::new();
+            let mut statuses = None;
+            let mut files = if let Some(source) = &files_from {
+                read_paths_from(source)?
+            } else if all_files {
+                let mut all = Vec::new();
+                let progress = walk_progress(false, "listing files");
+                walk_repo(&progress, false, |path| {
+                    all.push(path.to_string());
+                    true
+                });
+                progress.finish_and_clear();
+                all.sort();
+                all
+            } else if against_working_tree {
+                working_tree_changes(diff_opts)?
+            } else {
+                let entries = changed_files_in_ranges(
+                    &diff,
+                    DiffOptions {
+                        ignore_whitespace,
+                        ..diff_opts
+                    },
+                )?;
+                let files = entries.iter().map(|(path, _)| path.clone()).collect();
+                statuses = Some(entries.into_iter().collect::<HashMap<_, _>>());
+                files
+            };
+            files.retain(|f| path_filter.allows(f));
+            let (moved_within, moved_across) = if detect_renames {
+                let mut renames = renames_in_ranges(&diff)?;
+                renames.retain(|(from, to)| path_filter.allows(from) || path_filter.allows(to));
+                split_renames(&mapper, services.as_ref(), renames, &mut files)
+            } else {
+                (Vec::new(), Vec::new())
+            };
+            let moved_note = moved_within_note(&moved_within);
+            let impact = classify_files(&mapper, services.as_ref(), &load_ignore()?, &files);
+            let teams = by_team.then(|| impact.by_team(services.as_ref()));
+            let ImpactReport {
+                impacted_services: sorted_services,
+                services: service_files,
+                unmapped_files,
+                ignored_files,
+                ..
+            } = impact;
+            let service_files = files_by_service(service_files);
+            let unmapped_groups = group_unmapped_files(&unmapped_files);
+            // Mapped to a service nobody owns; only checked with owner metadata or when asked
+            let (shown_services, rollup) =
+                rollup_services(&sorted_services, &service_files, max_services);
+            let check_ownerless = fail_on_ownerless || services.is_some();
+            let ownerless: Vec<&String> = sorted_services
+                .iter()
+                .filter(|svc| check_ownerless && !has_owners(service_def(svc)))
+                .collect();
 
-            for file in &files {
-                match mapper.find_service(file) {
-                    Some(svc) => {
-                        service_files
-                            .entry(svc.to_string())
-                            .or_default()
-                            .push(file.clone());
+            match format {
+                ImpactedFormat::Json => {
+                    let mut services_detail = BTreeMap::new();
+                    for (svc, files) in &service_files {
+                        let mut detail = ServiceImpact::new(files.clone(), owner_def(svc));
+                        if include_unchanged_owners {
+                            detail.impacted = Some(true);
+                        }
+                        services_detail.insert(svc.clone(), detail);
                     }
-                    None => {
-                        unmapped_files.push(file.clone());
+                    if include_unchanged_owners {
+                        // Stable key set across PRs: every service from SERVICEOWNERS and services.yaml
+                        let mut known: Vec<String> = mapper
+                            .service_names()
+                            .iter()
+                            .map(|svc| canonical(services.as_ref(), svc).to_string())
+                            .collect();
+                        if let Some(services) = &services {
+                            known.extend(services.services.keys().cloned());
+                        }
+                        for svc in known {
+                            services_detail.entry(svc).or_insert_with(|| ServiceImpact {
+                                impacted: Some(false),
+                                ..ServiceImpact::new(Vec::new(), None)
+                            });
+                        }
                     }
+                    let payload = ImpactReport {
+                        schema_version: JSON_SCHEMA_VERSION,
+                        impacted_services: sorted_services.clone(),
+                        services: services_detail,
+                        unmapped_files: unmapped_files.clone(),
+                        ignored_files: ignored_files.clone(),
+                        ownerless_services: check_ownerless
+                            .then(|| ownerless.iter().map(|svc| svc.to_string()).collect()),
+                        moved_within_service: detect_renames.then(|| moved_within.clone()),
+                        moved_across_services: detect_renames.then(|| moved_across.clone()),
+                        unmapped_groups: group_unmapped.then(|| {
+                            unmapped_groups
+                                .iter()
+                                .map(|(dir, count)| UnmappedGroup {
+                                    dir: dir.clone(),
+                                    count: *count,
+                                })
+                                .collect()
+                        }),
+                        file_statuses: statuses.as_ref().map(|statuses| {
+                            files
+                                .iter()
+                                .filter_map(|f| statuses.get(f).map(|st| (f.clone(), *st)))
+                                .collect()
+                        }),
+                        teams: teams.as_ref().map(|t| t.teams.clone()),
+                        no_team: teams.as_ref().and_then(|t| t.no_team.clone()),
+                    };
+                    print_json(&payload, compact)?;
                 }
-            }
-            let mut sorted_services: Vec<String> = service_files.keys().cloned().collect();
-            sorted_services.sort();
-
-            match format.as_str() {
-                "json" => {
-                    let impacted_services: Vec<String> = sorted_services.clone();
-                    let mut services_detail = HashMap::new();
-                    for (svc, files) in &service_files {
-                        services_detail.insert(
-                            svc,
-                            serde_json::json!({
-                                "count": files.len(),
-                                "files": files
-                            }),
-                        );
+                ImpactedFormat::Tsv => {
+                    // Unmapped files get an empty service column
+                    if !no_header {
+                        println!("service\tfile_count\tfile");
+                    }
+                    for svc in &sorted_services {
+                        let files = &service_files[svc];
+                        for f in files {
+                            println!("{}\t{}\t{}", svc, files.len(), f);
+                        }
+                    }
+                    for f in &unmapped_files {
+                        println!("\t{}\t{}", unmapped_files.len(), f);
                     }
-                    let payload = serde_json::json!({
-                        "impacted_services": impacted_services,
-                        "services": services_detail,
-                        "unmapped_files": unmapped_files,
-                    });
-                    println!("{}", serde_json::to_string_pretty(&payload)?);
                 }
-                "markdown" => {
+                ImpactedFormat::GithubMarkdown => {
                     println!("### Impacted Services\n");
                     if sorted_services.is_empty() {
                         println!("_No services impacted_");
                     } else {
-                        println!("| Service | Files |");
-                        println!("| --- | --- |");
-                        for svc in &sorted_services {
-                            let count = service_files[svc].len();
-                            println!("| **{}** | {} |", svc, count);
+                        if let Some(note) = &rollup {
+                            println!("_{}_\n", note);
+                        }
+                        println!("| Service | Files | Owners | Links |");
+                        println!("| --- | --- | --- | --- |");
+                        for svc in &shown_services {
+                            let def = service_def(svc);
+                            println!(
+                                "| **{}** | {} | {} | {} |",
+                                markdown_escape(svc),
+                                service_files[svc].len(),
+                                def.map(mentions_cell).unwrap_or_default(),
+                                def.map(links_cell).unwrap_or_default()
+                            );
                         }
                     }
-                    if !unmapped_files.is_empty() {
-                        println!("\n### Unmapped Files\n");
-                        for f in &unmapped_files {
-                            println!("- `{}`", f);
+                    print_markdown_moves(&moved_across, moved_note.as_deref());
+                    print_markdown_unmapped(
+                        &unmapped_files,
+                        &ignored_files,
+                        group_unmapped.then_some(&unmapped_groups),
+                    );
+                    if !ownerless.is_empty() {
+                        println!("\n### Services Without Owners\n");
+                        for svc in &ownerless {
+                            println!(
+                                "- **{}** ({} files)",
+                                markdown_escape(svc),
+                                service_files[*svc].len()
+                            );
+                        }
+                    }
+                }
+                ImpactedFormat::Markdown => {
+                    if let Some(teams) = &teams {
+                        print_markdown_teams(teams);
+                    } else {
+                        println!("### Impacted Services\n");
+                        if sorted_services.is_empty() {
+                            println!("_No services impacted_");
+                        } else {
+                            if let Some(note) = &rollup {
+                                println!("_{}_\n", note);
+                            }
+                            if owners {
+                                println!("| Service | Files | Owners | On-call |");
+                                println!("| --- | --- | --- | --- |");
+                            } else {
+                                println!("| Service | Files |");
+                                println!("| --- | --- |");
+                            }
+                            for svc in &shown_services {
+                                let count = service_files[svc].len();
+                                if owners {
+                                    let def = owner_def(svc);
+                                    println!(
+                                        "| **{}** | {} | {} | {} |",
+                                        svc,
+                                        count,
+                                        def.map(owners_cell).unwrap_or_default(),
+                                        def.map(oncall_cell).unwrap_or_default()
+                                    );
+                                } else {
+                                    println!("| **{}** | {} |", svc, count);
+                                }
+                            }
+                        }
+                    }
+                    print_markdown_moves(&moved_across, moved_note.as_deref());
+                    print_markdown_unmapped(
+                        &unmapped_files,
+                        &ignored_files,
+                        group_unmapped.then_some(&unmapped_groups),
+                    );
+                    if !ownerless.is_empty() {
+                        println!("\n### Services Without Owners\n");
+                        for svc in &ownerless {
+                            println!(
+                                "- **{}** ({} files)",
+                                markdown_escape(svc),
+                                service_files[*svc].len()
+                            );
                         }
                     }
                 }
-                _ => {
-                    if !sorted_services.is_empty() {
+                ImpactedFormat::Changelog => {
+                    let label = release.clone().unwrap_or_else(|| match diff.as_slice() {
+                        [] => match files_from.as_deref() {
+                            Some("-") => "the listed files".to_string(),
+                            Some(list) => list.to_string(),
+                            None => resolve_diff_range(None),
+                        },
+                        ranges => ranges.join(", "),
+                    });
+                    println!("### Services changed in {}\n", label);
+                    if sorted_services.is_empty() {
+                        println!("_No services changed._");
+                    }
+                    for svc in &sorted_services {
+                        let count = service_files[svc].len();
+                        let plural = if count == 1 { "" } else { "s" };
+                        println!("- {} ({} file{})", svc, count, plural);
+                    }
+                    if !unmapped_files.is_empty() {
+                        println!("\n_{} unmapped files._", unmapped_files.len());
+                    }
+                }
+                ImpactedFormat::Text => {
+                    if let Some(teams) = teams.as_ref().filter(|t| !t.is_empty()) {
+                        println!("Impacted Teams:");
+                        for (team, impact) in team_rows(teams) {
+                            println!(
+                                "- {} ({})",
+                                team.unwrap_or(NO_TEAM_LABEL),
+                                impact.services.join(", ")
+                            );
+                            if show_files {
+                                for f in &impact.files {
+                                    println!("  - {}", f);
+                                }
+                            }
+                        }
+                    } else if teams.is_none() && !sorted_services.is_empty() {
                         println!("Impacted Services:");
-                        for svc in &sorted_services {
+                        if let Some(note) = &rollup {
+                            println!("({})", note);
+                        }
+                        for svc in &shown_services {
                             println!("- {}", svc);
+                            if let Some(def) = owner_def(svc) {
+                                print_service_details(def, "  ");
+                            }
                             if show_files {
                                 for f in &service_files[svc] {
                                     println!("  - {}", f);
@@ -185,71 +1158,417 @@ fn main() -> Result<()> {
                             }
                         }
                     }
+                    if !moved_across.is_empty() {
+                        println!("\nMoved Across Services:");
+                        for m in &moved_across {
+                            println!("- {}", m);
+                        }
+                    }
+                    if let Some(note) = &moved_note {
+                        println!("\n{}", note);
+                    }
                     if !unmapped_files.is_empty() {
                         println!("\nUnmapped Files:");
-                        for f in &unmapped_files {
+                        if group_unmapped {
+                            for (dir, count) in &unmapped_groups {
+                                println!("- {} ({} files)", dir, count);
+                            }
+                        } else {
+                            for f in &unmapped_files {
+                                println!("- {}", f);
+                            }
+                        }
+                    }
+                    if !ignored_files.is_empty() {
+                        println!("\nIgnored Files (intentionally unowned):");
+                        for f in &ignored_files {
                             println!("- {}", f);
                         }
                     }
-                }
-            }
-
-            if fail_on_unmapped && !unmapped_files.is_empty() {
-                std::process::exit(3);
-            }
-        }
-        Commands::Lint {
-            strict: _,
-            check_matches,
-        } => {
-            let mapper = ServiceMapper::from_file(&cli.serviceowners_file)?;
-            println!("Valid SERVICEOWNERS syntax");
-
-            if check_matches {
-                println!("Checking matches (this may take a while for large repos)...");
-                let mut used_rules = HashSet::new();
-                let walker = ignore::WalkBuilder::new(".").build();
-                for result in walker {
-                    match result {
-                        Ok(entry) => {
-                            if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
-                                let path = entry.path();
-                                if let Ok(rel) = path.strip_prefix(".") {
-                                    let path_str = rel.to_string_lossy();
-                                    let matches = mapper.explain_service(&path_str);
-                                    for m in matches {
-                                        used_rules.insert(m.pattern);
-                                    }
-                                }
+                    if !ownerless.is_empty() {
+                        println!("\nOwnerless Services (mapped, but no owners declared):");
+                        for svc in &ownerless {
+                            println!("- {}", svc);
+                            for f in &service_files[*svc] {
+                                println!("  - {}", f);
                             }
                         }
-                        Err(err) => eprintln!("Error walking repo: {}", err),
                     }
                 }
+            }
 
-                let mut unused_count = 0;
-                for pat in &mapper.patterns {
-                    if !used_rules.contains(pat) {
-                        println!("Warning: Pattern '{}' matches no files.", pat);
-                        unused_count += 1;
-                    }
+            if let Some(baseline) = &baseline {
+                if write_baseline {
+                    write_unmapped_baseline(baseline, &unmapped_files)?;
+                    eprintln!(
+                        "Wrote {} unmapped files to {:?}",
+                        unmapped_files.len(),
+                        baseline
+                    );
+                    return Ok(());
                 }
+            }
 
-                if unused_count == 0 {
-                    println!("All patterns match at least one file.");
-                } else {
-                    println!("Found {} unused patterns.", unused_count);
-                    // If strict check was enabled, we could exit non-zero here.
-                    // Python version: lint warnings cause exit 2 on strict.
-                    // The argument `strict` is available here.
-                    // But I'll leave it as warning for now unless asked.
-                }
+            let known = match &baseline {
+                Some(path) => read_unmapped_baseline(path)?,
+                None => HashSet::new(),
+            };
+            let new_unmapped = unmapped_files
+                .iter()
+                .filter(|f| !known.contains(*f))
+                .count();
+            if baseline.is_some() && unmapped_files.len() > new_unmapped {
+                eprintln!(
+                    "{} unmapped files are in the baseline; {} are new.",
+                    unmapped_files.len() - new_unmapped,
+                    new_unmapped
+                );
             }
-        }
-        Commands::Init {
-            codeowners,
+
+            let deprecated = deprecated_services(&sorted_services, services.as_ref());
+            for (svc, replacement) in &deprecated {
+                note_warning();
+                eprintln!(
+                    "Warning: {}",
+                    deprecation_message(svc, replacement.as_deref())
+                );
+            }
+
+            if fail_on_unmapped && new_unmapped > 0 {
+                std::process::exit(3);
+            }
+            if let Some(threshold) = fail_on_unmapped_threshold {
+                let kind = if baseline.is_some() {
+                    "new unmapped"
+                } else {
+                    "unmapped"
+                };
+                if new_unmapped > threshold {
+                    eprintln!(
+                        "{} {} files exceed the threshold of {}.",
+                        new_unmapped, kind, threshold
+                    );
+                    std::process::exit(3);
+                }
+                if new_unmapped > 0 {
+                    eprintln!(
+                        "{} {} files are within the threshold of {}.",
+                        new_unmapped, kind, threshold
+                    );
+                }
+            }
+            if fail_on_deprecated && !deprecated.is_empty() {
+                std::process::exit(4);
+            }
+            if fail_on_ownerless && !ownerless.is_empty() {
+                std::process::exit(5);
+            }
+        }
+        Commands::ExplainDiff {
+            diff,
+            format,
+            match_strategy,
+        } => {
+            let mapper = load_mapper()?.with_match_strategy(match_strategy);
+            let files = get_changed_files(diff.as_deref(), diff_opts)?;
+            explain_diff(&mapper, &files, format, compact)?;
+        }
+        Commands::Lint {
+            strict,
+            unused_services,
+            check_matches,
+            quiet,
+            skip_binary,
+            check_links,
+            fix,
+            format,
+        } => {
+            let json = format == TextOrJson::Json;
+            if fix {
+                check_fix_allowed(&cli)?;
+            }
+            let content = read_rules()?;
+            let mut report = LintReport {
+                json,
+                warnings: Vec::new(),
+                errors: Vec::new(),
+                removed: Vec::new(),
+            };
+            let diagnostics = ServiceMapper::validate_source(&content, &parse_opts);
+            if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+                for diag in diagnostics {
+                    report.push(diag);
+                }
+                if json {
+                    report.print(compact)?;
+                }
+                anyhow::bail!("{} syntax error(s) in {}", report.errors.len(), rules_name);
+            }
+            let mapper = ServiceMapper::parse_with(&content, &parse_opts)?;
+            if !json {
+                println!("Valid SERVICEOWNERS syntax");
+            }
+
+            for mut diag in diagnostics {
+                if strict && matches!(diag.code, "no-rules" | "conflict") {
+                    diag.severity = Severity::Error;
+                }
+                report.push(diag);
+            }
+            let services = load_services(&cli.services_file, &services_opts)?;
+            if let Some(services) = &services {
+                let online = check_links.then_some(http);
+                for diag in services_diagnostics(
+                    &mapper,
+                    services,
+                    &cli.services_file,
+                    strict,
+                    unused_services,
+                    online,
+                ) {
+                    report.push(diag);
+                }
+            }
+            if check_matches {
+                if !json {
+                    println!("Checking matches (this may take a while for large repos)...");
+                }
+                let unused = unused_rules(&mapper, quiet, skip_binary);
+                let unused_count = unused.len();
+                if fix && unused_count > 0 && unused_count == mapper.len() {
+                    anyhow::bail!(
+                        "No pattern matches any file; refusing to empty {:?} (run from the repo root?)",
+                        cli.serviceowners_file
+                    );
+                }
+                if fix && unused_count > 0 {
+                    let dead: HashSet<usize> =
+                        unused.iter().map(|&idx| mapper.lines[idx]).collect();
+                    std::fs::write(&cli.serviceowners_file, remove_lines(&content, &dead))
+                        .with_context(|| {
+                            format!(
+                                "Failed to write SERVICEOWNERS file at {:?}",
+                                cli.serviceowners_file
+                            )
+                        })?;
+                    if !json {
+                        println!(
+                            "Removed {} unused patterns from {:?}:",
+                            unused_count, cli.serviceowners_file
+                        );
+                    }
+                    for &idx in &unused {
+                        if !json {
+                            println!("- {} (line {})", mapper.patterns[idx], mapper.lines[idx]);
+                        }
+                        report
+                            .removed
+                            .push((mapper.patterns[idx].clone(), mapper.lines[idx]));
+                    }
+                } else {
+                    for &idx in &unused {
+                        report.push(unused_pattern(&mapper, idx));
+                    }
+                    if !json && unused_count == 0 {
+                        println!("All patterns match at least one file.");
+                    } else if !json {
+                        println!("Found {} unused patterns.", unused_count);
+                    }
+                }
+            }
+
+            if json {
+                report.print(compact)?;
+            }
+            if !report.errors.is_empty() {
+                std::process::exit(2);
+            }
+        }
+        Commands::Report {
+            format,
+            quiet,
+            skip_binary,
+        } => {
+            let ReportFormat::Html = format;
+            let mapper = load_mapper()?;
+            let services = load_services_with_inline(&cli.services_file, &services_opts, &mapper)?;
+            let ignore = load_ignore()?;
+            let (raw_counts, unmapped, ignored) =
+                ownership_counts(&mapper, &ignore, quiet, skip_binary);
+            let mut file_counts: HashMap<String, usize> = HashMap::new();
+            for (svc, n) in raw_counts {
+                *file_counts
+                    .entry(canonical(services.as_ref(), &svc).to_string())
+                    .or_default() += n;
+            }
+            print!(
+                "{}",
+                render_html_report(&mapper, services.as_ref(), &file_counts, unmapped, ignored)
+            );
+        }
+        Commands::WhyUnmapped {
+            path,
+            limit,
+            format,
+        } => {
+            let mapper = load_mapper()?;
+            let ignored = load_ignore()?.is_ignored(&path);
+            why_unmapped(&mapper, &path, limit, ignored, format, compact)?;
+        }
+        Commands::PatternTest {
+            pattern,
+            paths,
+            walk,
+            quiet,
+        } => {
+            let tester = PatternTester::new(&pattern, &parse_opts)?;
+            println!("Pattern: {} (normalized: {})", pattern, tester.normalized);
+            if walk {
+                let mut matched = Vec::new();
+                let progress = walk_progress(quiet, "testing pattern");
+                walk_repo(&progress, false, |path| {
+                    if tester.is_match(path) {
+                        matched.push(path.to_string());
+                    }
+                    true
+                });
+                progress.finish_and_clear();
+                matched.sort();
+                for path in &matched {
+                    println!("- {}", path);
+                }
+                println!("{} files match.", matched.len());
+            } else {
+                if paths.is_empty() {
+                    anyhow::bail!("Give paths to test, or --walk to test the repo");
+                }
+                for path in &paths {
+                    let verdict = if tester.is_match(path) {
+                        "match"
+                    } else {
+                        "no match"
+                    };
+                    println!("{:<9} {}", verdict, path);
+                }
+            }
+        }
+        Commands::Stats {
+            check_matches,
+            quiet,
+            skip_binary,
+            format,
+        } => {
+            let mapper = load_mapper()?;
+            let usage = check_matches.then(|| rule_usage(&mapper, quiet, skip_binary));
+            print_stats(&mapper, usage.as_deref(), format, compact)?;
+        }
+        Commands::Contacts { format } => {
+            let mapper = load_mapper()?;
+            let services = load_services(&cli.services_file, &services_opts)?;
+            print_contacts(&mapper, services.as_ref(), format, compact)?;
+        }
+        Commands::Schema { command } => {
+            let schema = match command {
+                SchemaKind::Impacted => schemars::schema_for!(ImpactReport),
+                SchemaKind::Lint => schemars::schema_for!(LintResult),
+                SchemaKind::WhoOwns => schemars::schema_for!(WhoOwnsReport),
+                SchemaKind::WhyUnmapped => schemars::schema_for!(WhyUnmappedReport),
+                SchemaKind::ExplainDiff => schemars::schema_for!(Vec<ExplainDiffRow>),
+                SchemaKind::Contacts => schemars::schema_for!(Vec<ContactRow>),
+                SchemaKind::Stats => schemars::schema_for!(StatsReport),
+                SchemaKind::Services => schemars::schema_for!(ServicesFile),
+            };
+            print_json(&schema, compact)?;
+        }
+        Commands::ValidateServices { format } => {
+            let json = format == TextOrJson::Json;
+            let path = &cli.services_file;
+            let files = if path.is_dir() {
+                let mut files: Vec<PathBuf> = std::fs::read_dir(path)
+                    .with_context(|| format!("Failed to read services directory at {:?}", path))?
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|p| {
+                        p.is_file()
+                            && matches!(
+                                p.extension().and_then(|e| e.to_str()),
+                                Some("yaml" | "yml")
+                            )
+                    })
+                    .collect();
+                files.sort();
+                files
+            } else if path.exists() {
+                vec![path.clone()]
+            } else {
+                anyhow::bail!("{:?} not found (use --services-file)", path);
+            };
+            let mut report = LintReport {
+                json,
+                warnings: Vec::new(),
+                errors: Vec::new(),
+                removed: Vec::new(),
+            };
+            for file in &files {
+                let content = std::fs::read_to_string(file)
+                    .with_context(|| format!("Failed to read services file at {:?}", file))?;
+                for mut diag in ServicesFile::validate_source(&content) {
+                    diag.message = match diag.line {
+                        Some(line) => format!("{}:{}: {}", file.display(), line, diag.message),
+                        None => format!("{}: {}", file.display(), diag.message),
+                    };
+                    report.push(diag);
+                }
+            }
+            if json {
+                report.print(compact)?;
+            } else if report.errors.is_empty() {
+                println!("{} is valid", path.display());
+            }
+            if !report.errors.is_empty() {
+                std::process::exit(2);
+            }
+        }
+        Commands::Doctor { diff } => doctor(DoctorOptions {
+            rules_file: &cli.serviceowners_file,
+            rules_content: cli.serviceowners_content.as_deref(),
+            services_file: &cli.services_file,
+            parse_opts: &parse_opts,
+            services_opts: &services_opts,
+            diff: diff.as_deref(),
+            diff_opts,
+        })?,
+        Commands::MigrateServices { write } => {
+            let path = &cli.services_file;
+            if path.is_dir() {
+                anyhow::bail!(
+                    "{:?} is a directory; migrate each file by passing it to --services-file",
+                    path
+                );
+            }
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read services file at {:?}", path))?;
+            match migrate_services_yaml(&content)
+                .with_context(|| format!("Failed to migrate {:?}", path))?
+            {
+                None => println!(
+                    "{:?} is already at version {}",
+                    path, SERVICES_SCHEMA_VERSION
+                ),
+                Some(migrated) if write => {
+                    std::fs::write(path, migrated)?;
+                    println!("Migrated {:?} to version {}", path, SERVICES_SCHEMA_VERSION);
+                }
+                Some(migrated) => print!("{}", migrated),
+            }
+        }
+        Commands::Init {
+            codeowners,
             write,
+            output,
             force,
+            group_by_service,
+            stop_words,
+            keep_case,
         } => {
             let co_path = if let Some(p) = codeowners {
                 p
@@ -263,203 +1582,3350 @@ fn main() -> Result<()> {
                     anyhow::anyhow!("CODEOWNERS file not found (use --codeowners)")
                 })?
             };
-            let out = init_from_codeowners(&co_path)?;
-            if write {
-                if cli.serviceowners_file.exists() && !force {
-                    anyhow::bail!(
-                        "{:?} already exists (use --force to overwrite)",
-                        cli.serviceowners_file
-                    );
+            let opts = InitOptions {
+                group_by_service,
+                stop_words,
+                keep_case,
+            };
+            let out = init_from_codeowners_with(&co_path, &opts)?;
+            let target = output.or_else(|| write.then(|| cli.serviceowners_file.clone()));
+            match target {
+                Some(target) => {
+                    if target.exists() && !force {
+                        anyhow::bail!("{:?} already exists (use --force to overwrite)", target);
+                    }
+                    std::fs::write(&target, out)
+                        .with_context(|| format!("Failed to write {:?}", target))?;
+                    println!("Wrote {:?}", target);
                 }
-                std::fs::write(&cli.serviceowners_file, out)?;
-                println!("Wrote {:?}", cli.serviceowners_file);
-            } else {
-                println!("{}", out);
+                None => println!("{}", out),
             }
         }
+        Commands::ExportCodeowners {
+            codeowners,
+            write,
+            force,
+        } => {
+            let mapper = load_mapper()?;
+            let services = load_services_with_inline(&cli.services_file, &services_opts, &mapper)?
+                .unwrap_or(ServicesFile {
+                    version: None,
+                    services: BTreeMap::new(),
+                });
+            let ownerless: BTreeSet<&str> = (0..mapper.len())
+                .filter(|&idx| {
+                    let svc = &mapper.service_names()[idx];
+                    let defined = services.get(svc).and_then(|d| d.owners.as_ref());
+                    defined.is_none_or(|o| o.is_empty()) && mapper.rule_owners(idx).is_empty()
+                })
+                .map(|idx| mapper.service_names()[idx].as_str())
+                .collect();
+            for svc in &ownerless {
+                eprintln!(
+                    "Warning: service '{}' has no owners; its patterns are exported unowned",
+                    svc
+                );
+                note_warning();
+            }
+            for exclusion in mapper.exclusions() {
+                eprintln!(
+                    "Warning: exclusion '!{} {}' (line {}) can't be expressed in CODEOWNERS and is left out",
+                    exclusion.pattern, exclusion.service, exclusion.line
+                );
+                note_warning();
+            }
+            let out = export_codeowners(&mapper, &services);
+            if !write {
+                print!("{}", out);
+                return Ok(());
+            }
+            if codeowners.exists() && !force {
+                anyhow::bail!("{:?} already exists (use --force to overwrite)", codeowners);
+            }
+            if let Some(dir) = codeowners.parent().filter(|d| !d.as_os_str().is_empty()) {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("Failed to create {:?}", dir))?;
+            }
+            std::fs::write(&codeowners, out)
+                .with_context(|| format!("Failed to write {:?}", codeowners))?;
+            println!("Wrote {:?}", codeowners);
+        }
         Commands::Action {
             diff,
             comment,
             fail_on_unmapped,
             strict_lint,
+            template,
+            skip_empty,
+            fail_on_deprecated,
+            baseline_diff,
+            baseline_services,
+            platform,
+            comment_footer,
         } => {
-            action_runner(
+            let mapper = load_mapper()?;
+            let services = load_services_with_inline(&cli.services_file, &services_opts, &mapper)?;
+            action_runner(ActionOptions {
                 diff,
-                &cli.serviceowners_file,
-                &cli.services_file,
-                comment == "true",
-                fail_on_unmapped == "true",
-                strict_lint == "true",
-            )?;
+                mapper,
+                services: services.as_ref(),
+                ignore: load_ignore()?,
+                comment: comment == "true",
+                fail_on_unmapped: fail_on_unmapped == "true",
+                strict_lint: strict_lint == "true",
+                template: template.as_deref(),
+                skip_empty: skip_empty == "true",
+                http,
+                fail_on_deprecated: fail_on_deprecated == "true",
+                baseline_diff,
+                baseline_services,
+                platform,
+                diff_opts,
+                comment_footer: comment_footer.as_deref(),
+            })?;
         }
     }
 
     Ok(())
 }
 
-fn get_changed_files(diff_arg: Option<&str>) -> Result<Vec<String>> {
-    let args = match diff_arg {
-        Some(range) => vec!["diff", "--name-only", range],
-        None => vec!["diff", "--name-only", "HEAD~1", "HEAD"],
+/// Outcome of one `doctor` check
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CheckStatus {
+    Ok,
+    Warn,
+    Fail,
+}
+
+/// Prints one checklist line and, below it, how to fix the problem.
+fn report_check(status: CheckStatus, summary: &str, fix: Option<&str>) {
+    let tag = match status {
+        CheckStatus::Ok => "[ok]  ",
+        CheckStatus::Warn => {
+            note_warning();
+            "[warn]"
+        }
+        CheckStatus::Fail => "[fail]",
+    };
+    println!("{} {}", tag, summary);
+    if let Some(fix) = fix {
+        println!("       fix: {}", fix);
+    }
+}
+
+struct DoctorOptions<'a> {
+    rules_file: &'a Path,
+    /// `--serviceowners-content`, which replaces the file
+    rules_content: Option<&'a str>,
+    services_file: &'a Path,
+    parse_opts: &'a ParseOptions,
+    services_opts: &'a ServicesOptions,
+    diff: Option<&'a str>,
+    diff_opts: DiffOptions,
+}
+
+/// Runs the setup checks in order, printing a checklist.
+fn doctor(opts: DoctorOptions<'_>) -> Result<()> {
+    let mut failed = 0;
+    let mut check = |status: CheckStatus, summary: &str, fix: Option<&str>| {
+        if status == CheckStatus::Fail {
+            failed += 1;
+        }
+        report_check(status, summary, fix);
     };
 
-    let output = Command::new("git")
-        .args(&args)
-        .output()
-        .context("Failed to run git diff")?;
+    let git_version = Command::new("git").arg("--version").output();
+    let git_ok = match &git_version {
+        Ok(out) if out.status.success() => {
+            let version = String::from_utf8_lossy(&out.stdout);
+            check(
+                CheckStatus::Ok,
+                &format!("git works ({})", version.trim()),
+                None,
+            );
+            true
+        }
+        _ => {
+            check(
+                CheckStatus::Fail,
+                "git could not be run",
+                Some("install git and make sure it is on PATH"),
+            );
+            false
+        }
+    };
 
-    if !output.status.success() {
-        anyhow::bail!(
-            "git diff failed: {}",
-            String::from_utf8_lossy(&output.stderr)
+    let toplevel = git_ok
+        .then(|| {
+            let out = Command::new("git")
+                .args(["rev-parse", "--show-toplevel"])
+                .output()
+                .ok()?;
+            out.status
+                .success()
+                .then(|| PathBuf::from(String::from_utf8_lossy(&out.stdout).trim()))
+        })
+        .flatten();
+    match &toplevel {
+        Some(root) => {
+            check(
+                CheckStatus::Ok,
+                &format!("inside a git repository ({})", root.display()),
+                None,
+            );
+            let cwd = std::env::current_dir()
+                .ok()
+                .and_then(|d| d.canonicalize().ok());
+            if cwd.is_some_and(|cwd| root.canonicalize().is_ok_and(|root| root != cwd)) {
+                check(
+                    CheckStatus::Warn,
+                    "not running from the repository root; rules are matched against root-relative paths",
+                    Some("cd to the repository root before running sowners"),
+                );
+            }
+        }
+        None if git_ok => check(
+            CheckStatus::Fail,
+            "not inside a git repository",
+            Some("run sowners from a clone of your repository"),
+        ),
+        None => {}
+    }
+
+    let mapper = match opts.rules_content {
+        Some(content) => Some(ServiceMapper::parse_with(content, opts.parse_opts)),
+        None if !opts.rules_file.exists() => {
+            check(
+                CheckStatus::Fail,
+                &format!("{:?} not found", opts.rules_file),
+                Some("create it (`sowners init` converts CODEOWNERS) or pass --serviceowners-file"),
+            );
+            None
+        }
+        None => Some(ServiceMapper::from_file_with(
+            opts.rules_file,
+            opts.parse_opts,
+        )),
+    };
+    let rules_name = match opts.rules_content {
+        Some(_) => "--serviceowners-content".to_string(),
+        None => format!("{:?}", opts.rules_file),
+    };
+    let mapper = match mapper {
+        Some(Ok(mapper)) if mapper.is_empty() => {
+            check(
+                CheckStatus::Warn,
+                &format!("{} has no rules; every file is unmapped", rules_name),
+                Some("add `pattern service` lines"),
+            );
+            Some(mapper)
+        }
+        Some(Ok(mapper)) => {
+            check(
+                CheckStatus::Ok,
+                &format!("{} parses ({} rule(s))", rules_name, mapper.len()),
+                None,
+            );
+            Some(mapper)
+        }
+        Some(Err(e)) => {
+            check(
+                CheckStatus::Fail,
+                &format!("{} does not parse: {:#}", rules_name, e),
+                Some("run `sowners lint` for every problem with line numbers"),
+            );
+            None
+        }
+        None => None,
+    };
+
+    let services = if opts.services_file.exists() {
+        match ServicesFile::load_with(opts.services_file, opts.services_opts) {
+            Ok(services) => {
+                check(
+                    CheckStatus::Ok,
+                    &format!(
+                        "{:?} parses ({} service(s))",
+                        opts.services_file,
+                        services.services.len()
+                    ),
+                    None,
+                );
+                Some(services)
+            }
+            Err(e) => {
+                check(
+                    CheckStatus::Fail,
+                    &format!("{:?} does not parse: {:#}", opts.services_file, e),
+                    Some("fix the YAML, or run `sowners migrate-services` for an old file"),
+                );
+                None
+            }
+        }
+    } else {
+        check(
+            CheckStatus::Warn,
+            &format!(
+                "{:?} not found; owners come only from inline rules",
+                opts.services_file
+            ),
+            Some("create services.yaml for owners and contacts, or pass --services-file"),
         );
+        None
+    };
+
+    if let (Some(mapper), Some(services)) = (&mapper, &services) {
+        let inline = mapper.inline_services();
+        let undefined: BTreeSet<&str> = mapper
+            .service_names()
+            .iter()
+            .map(String::as_str)
+            .filter(|svc| services.get(svc).is_none() && !inline.services.contains_key(*svc))
+            .collect();
+        if undefined.is_empty() {
+            check(
+                CheckStatus::Ok,
+                "every service in the rules is defined",
+                None,
+            );
+        } else {
+            let names: Vec<&str> = undefined.into_iter().collect();
+            check(
+                CheckStatus::Warn,
+                &format!(
+                    "{} service(s) used in rules but not defined: {}",
+                    names.len(),
+                    names.join(", ")
+                ),
+                Some(&format!("add them to {:?}", opts.services_file)),
+            );
+        }
     }
 
-    let stdout = String::from_utf8(output.stdout)?;
-    Ok(stdout.lines().map(|s| s.to_string()).collect())
+    if toplevel.is_some() {
+        let range = resolve_diff_range(opts.diff);
+        match get_changed_files(Some(&range), opts.diff_opts) {
+            Ok(files) => check(
+                CheckStatus::Ok,
+                &format!(
+                    "diff range {} resolves ({} changed file(s))",
+                    range,
+                    files.len()
+                ),
+                None,
+            ),
+            Err(e) => {
+                let reason = format!("{:#}", e);
+                check(
+                    CheckStatus::Fail,
+                    &format!(
+                        "diff range {} does not resolve: {}",
+                        range,
+                        reason.lines().next().unwrap_or_default().trim()
+                    ),
+                    Some("pass --diff base...HEAD, and fetch the base (actions/checkout `fetch-depth: 0`)"),
+                );
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} check(s) failed", failed);
+    }
+    Ok(())
 }
 
-fn action_runner(
-    diff_arg: Option<String>,
-    serviceowners: &Path,
-    _services: &Path,
-    comment: bool,
-    fail_on_unmapped: bool,
-    _strict_lint: bool,
+/// Prints the near misses for an unmapped `path` and a suggested rule.
+fn why_unmapped(
+    mapper: &ServiceMapper,
+    path: &str,
+    limit: usize,
+    ignored: bool,
+    format: TextOrJson,
+    compact: bool,
 ) -> Result<()> {
-    // 1. Determine diff
-    let diff = if let Some(d) = diff_arg {
-        d
+    let json = format == TextOrJson::Json;
+    if let Some(idx) = mapper.winning_rule(path) {
+        let (pattern, line) = (&mapper.patterns[idx], mapper.lines[idx]);
+        let service = &mapper.service_names()[idx];
+        if json {
+            let out = WhyUnmappedReport::Mapped {
+                schema_version: JSON_SCHEMA_VERSION,
+                path: path.to_string(),
+                service: service.clone(),
+                pattern: pattern.clone(),
+                line,
+            };
+            return print_json(&out, compact);
+        }
+        println!(
+            "{} is mapped to {} by {} (line {})",
+            path, service, pattern, line
+        );
+        return Ok(());
+    }
+
+    let query = normalize_query_path(path);
+    let out_of_scope = mapper
+        .root_scope()
+        .filter(|scope| !query.starts_with(scope));
+    let misses = mapper.near_misses(path, limit);
+    let segments: Vec<&str> = query.trim_end_matches('/').split('/').collect();
+    let cut = misses.first().map_or(0, |m| m.shared_segments) + 1;
+    let suggested_pattern = if cut < segments.len() {
+        format!("{}/**", segments[..cut].join("/"))
     } else {
-        "HEAD~1...HEAD".to_string()
+        segments.join("/")
+    };
+    // Only name a service when every closest rule agrees on it
+    let closest = misses.iter().take_while(|m| m.shared_segments + 1 == cut);
+    let mut closest_services = closest.map(|m| m.service);
+    let suggested_service = match closest_services.next() {
+        Some(first) if closest_services.all(|svc| svc == first) => first,
+        _ => "<service>",
     };
 
-    let mapper = ServiceMapper::from_file(serviceowners)?;
-    let files = get_changed_files(Some(&diff))?;
-    let mut impacted_services: HashSet<String> = HashSet::new();
-    let mut unmapped_files = Vec::new();
+    if json {
+        let out = WhyUnmappedReport::Unmapped {
+            schema_version: JSON_SCHEMA_VERSION,
+            path: path.to_string(),
+            service: None,
+            out_of_scope: out_of_scope.map(str::to_string),
+            ignored,
+            near_misses: misses.iter().map(NearMissRow::from).collect(),
+            suggested_rule: format!("{} {}", suggested_pattern, suggested_service),
+        };
+        return print_json(&out, compact);
+    }
 
-    for file in &files {
-        match mapper.find_service(file) {
-            Some(svc) => {
-                impacted_services.insert(svc.to_string());
-            }
-            None => {
-                unmapped_files.push(file.clone());
-            }
+    println!("{} is unmapped.", path);
+    if let Some(scope) = out_of_scope {
+        println!(
+            "It is outside the root scope {}, so no rule can match it.",
+            scope
+        );
+        return Ok(());
+    }
+    if ignored {
+        println!("It is listed in the ignore file, so it counts as ignored rather than unmapped.");
+    }
+    if misses.is_empty() {
+        println!("\nNo rule shares a leading directory with it.");
+    } else {
+        println!("\nClosest rules:");
+        for m in &misses {
+            println!(
+                "- {} -> {} (line {}; shares {}/)",
+                m.pattern,
+                m.service,
+                m.line,
+                segments[..m.shared_segments].join("/")
+            );
         }
     }
+    println!(
+        "\nSuggested rule:\n  {} {}",
+        suggested_pattern, suggested_service
+    );
+    Ok(())
+}
 
-    // GITHUB_OUTPUT
-    if let Ok(path) = std::env::var("GITHUB_OUTPUT") {
-        let mut f = std::fs::OpenOptions::new().append(true).open(path)?;
-        use std::io::Write;
-        let services_vec: Vec<&String> = impacted_services.iter().collect();
-        let services_json = serde_json::to_string(&services_vec)?;
-        let unmapped_json = serde_json::to_string(&unmapped_files)?;
-        writeln!(f, "impacted_services={}", services_json)?;
-        writeln!(f, "unmapped_files={}", unmapped_json)?;
+/// Prints, per changed file, the winning rule and any runner-up matches, in
+/// the order they would take over under the mapper's match strategy.
+fn explain_diff(
+    mapper: &ServiceMapper,
+    files: &[String],
+    format: TextOrJson,
+    compact: bool,
+) -> Result<()> {
+    let explained: Vec<_> = files
+        .iter()
+        .map(|f| (f, mapper.ranked_matches(f)))
+        .collect();
+
+    if format == TextOrJson::Json {
+        let rows: Vec<ExplainDiffRow> = explained
+            .iter()
+            .map(|(path, matches)| ExplainDiffRow::new(path, matches))
+            .collect();
+        print_json(&rows, compact)?;
+        return Ok(());
     }
 
-    // Markdown Body
-    let mut md = String::new();
-    md.push_str("### 🧭 ServiceOwners Impact Report\n\n");
-    md.push_str(&format!("Diff: `{}`\n\n", diff));
-    if impacted_services.is_empty() {
-        md.push_str("_No services impacted_");
+    let multi_source = mapper.sources().len() > 1;
+    let rows: Vec<(String, String, String, String)> = explained
+        .iter()
+        .map(|(path, matches)| {
+            let (service, rule) = match matches.first() {
+                Some(m) if m.fallback => (m.service.to_string(), "(default service)".to_string()),
+                Some(m) => (
+                    m.service.to_string(),
+                    format!("{} ({})", m.pattern, rule_location(m, multi_source)),
+                ),
+                None => ("Unmapped".to_string(), "-".to_string()),
+            };
+            let runner_up: Vec<String> = matches
+                .iter()
+                .skip(1)
+                .map(|m| {
+                    format!(
+                        "{} -> {} ({})",
+                        m.pattern,
+                        m.service,
+                        rule_location(m, multi_source)
+                    )
+                })
+                .collect();
+            (path.to_string(), service, rule, runner_up.join("; "))
+        })
+        .collect();
+
+    let header = (
+        "FILE".to_string(),
+        "SERVICE".to_string(),
+        "RULE".to_string(),
+        "RUNNER-UP".to_string(),
+    );
+    let w_file = rows
+        .iter()
+        .map(|r| r.0.len())
+        .chain([header.0.len()])
+        .max()
+        .unwrap_or(0);
+    let w_svc = rows
+        .iter()
+        .map(|r| r.1.len())
+        .chain([header.1.len()])
+        .max()
+        .unwrap_or(0);
+    let w_rule = rows
+        .iter()
+        .map(|r| r.2.len())
+        .chain([header.2.len()])
+        .max()
+        .unwrap_or(0);
+    for (file, svc, rule, runner_up) in std::iter::once(&header).chain(rows.iter()) {
+        let line = format!(
+            "{:<w_file$}  {:<w_svc$}  {:<w_rule$}  {}",
+            file, svc, rule, runner_up
+        );
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// Writes a JSON payload to stdout, pretty-printed unless `compact`.
+fn print_json<T: serde::Serialize>(value: &T, compact: bool) -> Result<()> {
+    let out = if compact {
+        serde_json::to_string(value)?
     } else {
-        md.push_str("| Service | \n| --- | \n");
-        let mut sorted: Vec<&String> = impacted_services.iter().collect();
-        sorted.sort();
-        for svc in sorted {
-            md.push_str(&format!("| **{}** | \n", svc));
+        serde_json::to_string_pretty(value)?
+    };
+    println!("{}", out);
+    Ok(())
+}
+
+/// Loads services.yaml if present. The metadata file is optional, so a missing
+/// file is not an error.
+fn load_services(path: &Path, opts: &ServicesOptions) -> Result<Option<ServicesFile>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    ServicesFile::load_with(path, opts).map(Some)
+}
+
+/// `who-owns --explain` list: every matching rule, marking the one that
+/// decided `path` and the `!pattern` that knocked out any excluded one.
+fn print_explain_matches(mapper: &ServiceMapper, path: &str, specificity: bool) {
+    let matches = mapper.explain_service(path);
+    if matches.is_empty() {
+        println!("\nNo matches found.");
+        return;
+    }
+    println!("\nMatches:");
+    for m in matches {
+        if m.fallback {
+            println!("- (no rule) -> {} (default service) <== chosen", m.service);
+            continue;
         }
+        let mark = match m.excluded_by {
+            Some(e) => format!(" (excluded by !{} on line {})", e.pattern, e.line),
+            None if m.chosen => " <== chosen".to_string(),
+            None => String::new(),
+        };
+        let mut score = if specificity {
+            format!(" (score {})", m.specificity)
+        } else {
+            String::new()
+        };
+        if m.priority != 0 {
+            score.push_str(&format!(" (priority {})", m.priority));
+        }
+        let origin = match m.source {
+            Some(source) => format!(" ({}:{})", source.display(), m.line),
+            None => format!(" (line {})", m.line),
+        };
+        let normalized = if m.normalized != m.pattern {
+            format!(" [{}]", m.normalized)
+        } else {
+            String::new()
+        };
+        println!(
+            "- {}{} -> {}{}{}{}",
+            m.pattern, normalized, m.service, origin, score, mark
+        );
     }
-    md.push_str("\n<!-- serviceowners:begin -->\n<!-- serviceowners:end -->");
+}
 
-    // GITHUB_STEP_SUMMARY
-    if let Ok(path) = std::env::var("GITHUB_STEP_SUMMARY") {
-        let mut f = std::fs::OpenOptions::new().append(true).open(path)?;
-        use std::io::Write;
-        f.write_all(md.as_bytes())?;
+/// `line N`, or `line N of FILE` once rules come from more than one file.
+fn rule_location(m: &ExplainMatch, multi_source: bool) -> String {
+    match m.source.filter(|_| multi_source) {
+        Some(source) => format!("line {} of {}", m.line, source.display()),
+        None => format!("line {}", m.line),
     }
+}
 
-    // PR Commenting
-    if comment {
-        if let Ok(token) = std::env::var("GITHUB_TOKEN") {
-            if let Ok(event_path) = std::env::var("GITHUB_EVENT_PATH") {
-                if let Ok(content) = std::fs::read_to_string(event_path) {
-                    if let Ok(json) = serde_json::from_str::<serde_json::Value>(&content) {
-                        if let Some(pr_num) = json
-                            .get("pull_request")
-                            .and_then(|pr| pr.get("number"))
-                            .and_then(|n| n.as_i64())
-                        {
-                            if let Ok(repo) = std::env::var("GITHUB_REPOSITORY") {
-                                post_pr_comment(&token, &repo, pr_num, &md)?;
-                            }
-                        }
-                    }
-                }
-            }
-        }
+/// `--path-filter` globs, in SERVICEOWNERS pattern syntax (`*.sql` matches
+/// at any depth). No globs lets every path through.
+struct PathFilter(Vec<PatternTester>);
+
+impl PathFilter {
+    fn new(globs: &[String], opts: &ParseOptions) -> Result<Self> {
+        globs
+            .iter()
+            .map(|glob| {
+                PatternTester::new(glob, opts)
+                    .with_context(|| format!("Invalid --path-filter '{}'", glob))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
     }
 
-    if fail_on_unmapped && !unmapped_files.is_empty() {
-        std::process::exit(3);
+    fn allows(&self, path: &str) -> bool {
+        self.0.is_empty() || self.0.iter().any(|glob| glob.is_match(path))
     }
+}
 
-    Ok(())
+/// Non-empty, trimmed lines from stdin.
+fn read_stdin_paths() -> Result<Vec<String>> {
+    use std::io::BufRead;
+    let mut paths = Vec::new();
+    for line in std::io::stdin().lock().lines() {
+        let line = line.context("Failed to read paths from stdin")?;
+        let line = line.trim();
+        if !line.is_empty() {
+            paths.push(line.to_string());
+        }
+    }
+    Ok(paths)
 }
 
-fn post_pr_comment(token: &str, repo: &str, pr_num: i64, body: &str) -> Result<()> {
-    let client = reqwest::blocking::Client::new();
-    let url = format!(
-        "https://api.github.com/repos/{}/issues/{}/comments",
-        repo, pr_num
-    );
+/// Paths listed in `source`, one per line, or from stdin when it's `-`.
+/// Paths are cleaned up like query paths, so `./src/a.rs` reads as `src/a.rs`.
+fn read_paths_from(source: &str) -> Result<Vec<String>> {
+    let paths = if source == "-" {
+        read_stdin_paths()?
+    } else {
+        let content = std::fs::read_to_string(source)
+            .with_context(|| format!("Failed to read file list {:?}", source))?;
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    Ok(paths
+        .iter()
+        .map(|path| normalize_query_path(path).into_owned())
+        .collect())
+}
 
-    let marker = "<!-- serviceowners:begin -->";
-    let resp = client
-        .get(&url)
-        .header("Authorization", format!("Bearer {}", token))
-        .header("User-Agent", "serviceowners-rust")
-        .send()?
-        .json::<Vec<serde_json::Value>>()?;
+/// Like `load_services`, plus services known only from inline `@owner`
+/// tokens in SERVICEOWNERS. services.yaml entries always take precedence.
+fn load_services_with_inline(
+    path: &Path,
+    opts: &ServicesOptions,
+    mapper: &ServiceMapper,
+) -> Result<Option<ServicesFile>> {
+    let services = load_services(path, opts)?;
+    let inline = mapper.inline_services();
+    if inline.services.is_empty() {
+        return Ok(services);
+    }
+    let mut merged = services.unwrap_or(ServicesFile {
+        version: None,
+        services: BTreeMap::new(),
+    });
+    for (name, def) in inline.services {
+        if merged.get(&name).is_none() {
+            merged.services.insert(name, def);
+        }
+    }
+    Ok(Some(merged))
+}
+
+fn print_service_details(def: &ServiceDef, indent: &str) {
+    if let Some(owners) = &def.owners {
+        let names: Vec<String> = owners.iter().map(owner_label).collect();
+        println!("{}Owners: {}", indent, names.join(", "));
+    }
+    if let Some(contact) = &def.contact {
+        let entries: Vec<String> = contact
+            .entries()
+            .iter()
+            .map(|(k, v)| format!("{}: {}", k, v))
+            .collect();
+        if !entries.is_empty() {
+            println!("{}Contact: {}", indent, entries.join(", "));
+        }
+    }
+}
 
-    let mut comment_id = None;
-    for c in resp {
-        if let Some(b) = c.get("body").and_then(|s| s.as_str()) {
-            if b.contains(marker) {
-                comment_id = c.get("id").and_then(|id| id.as_i64());
-                break;
+fn print_markdown_unmapped(
+    unmapped_files: &[String],
+    ignored_files: &[String],
+    groups: Option<&Vec<(String, usize)>>,
+) {
+    if !unmapped_files.is_empty() {
+        println!("\n### Unmapped Files\n");
+        match groups {
+            Some(groups) => {
+                for (dir, count) in groups {
+                    println!("- `{}` ({} files)", dir, count);
+                }
+            }
+            None => {
+                for f in unmapped_files {
+                    println!("- `{}`", f);
+                }
             }
         }
     }
+    if !ignored_files.is_empty() {
+        println!("\n{}", ignored_note(ignored_files.len()));
+    }
+}
 
-    let payload = serde_json::json!({ "body": body });
+/// Markdown note for files excluded by the ignore file.
+fn ignored_note(count: usize) -> String {
+    format!(
+        "_{} intentionally unowned file(s) ignored via `.sownersignore`_",
+        count
+    )
+}
 
-    if let Some(id) = comment_id {
-        let update_url = format!(
-            "https://api.github.com/repos/{}/issues/comments/{}",
-            repo, id
-        );
-        client
-            .patch(&update_url)
-            .header("Authorization", format!("Bearer {}", token))
-            .header("User-Agent", "serviceowners-rust")
-            .json(&payload)
-            .send()?;
-        println!("Updated comment {}", id);
-    } else {
-        client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", token))
-            .header("User-Agent", "serviceowners-rust")
-            .json(&payload)
-            .send()?;
-        println!("Created comment on PR #{}", pr_num);
+/// Owners as GitHub mentions, so teams and users are notified.
+fn mentions_cell(def: &ServiceDef) -> String {
+    def.owners
+        .iter()
+        .flatten()
+        .map(owner_mention)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn links_cell(def: &ServiceDef) -> String {
+    let mut links = Vec::new();
+    if let Some(docs) = &def.docs {
+        links.push(format!("[docs]({})", docs));
     }
+    if let Some(runbook) = &def.runbook {
+        links.push(format!("[runbook]({})", runbook));
+    }
+    links.join(" · ")
+}
 
+/// Escapes characters that would break a Markdown table cell or be read as formatting.
+fn markdown_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if matches!(
+            c,
+            '\\' | '|' | '*' | '_' | '`' | '[' | ']' | '<' | '>' | '#'
+        ) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+fn owners_cell(def: &ServiceDef) -> String {
+    def.owners
+        .iter()
+        .flatten()
+        .map(owner_label)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Markdown cell pointing at who to page: the escalation URL as a link
+/// and/or the PagerDuty service id.
+fn oncall_cell(def: &ServiceDef) -> String {
+    let mut parts = Vec::new();
+    if let Some(contact) = &def.contact {
+        if let Some(url) = &contact.oncall_url {
+            parts.push(format!("[on-call]({})", url));
+        }
+        if let Some(pd) = &contact.pagerduty {
+            parts.push(format!("PagerDuty `{}`", pd));
+        }
+    }
+    parts.join(" · ")
+}
+
+/// The lint checks that need services.yaml (`alias`, `undefined-service`,
+/// `unused-service`, `link`).
+fn services_diagnostics(
+    mapper: &ServiceMapper,
+    services: &ServicesFile,
+    services_path: &Path,
+    strict: bool,
+    unused_services: bool,
+    online: Option<HttpOptions>,
+) -> Vec<Diagnostic> {
+    let mut diags = Vec::new();
+    for (idx, svc) in mapper.service_names().iter().enumerate() {
+        let name = services.canonical_name(svc);
+        if name != svc {
+            diags.push(Diagnostic {
+                line: Some(mapper.lines[idx]),
+                severity: Severity::Warning,
+                code: "alias",
+                pattern: Some(mapper.patterns[idx].clone()),
+                message: format!(
+                    "Rule '{}' on line {} uses alias '{}', canonical is '{}'.",
+                    mapper.patterns[idx], mapper.lines[idx], svc, name
+                ),
+            });
+        }
+    }
+    let inline = mapper.inline_services();
+    for (idx, svc) in mapper.service_names().iter().enumerate() {
+        if services.get(svc).is_some() || inline.services.contains_key(svc) {
+            continue;
+        }
+        diags.push(Diagnostic {
+            line: Some(mapper.lines[idx]),
+            severity: if strict {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            code: "undefined-service",
+            pattern: Some(mapper.patterns[idx].clone()),
+            message: format!(
+                "Rule '{}' on line {} maps to '{}', which is not defined in {}.",
+                mapper.patterns[idx],
+                mapper.lines[idx],
+                svc,
+                services_path.display()
+            ),
+        });
+    }
+    if unused_services {
+        let used: HashSet<&str> = mapper
+            .service_names()
+            .iter()
+            .map(|svc| services.canonical_name(svc))
+            .collect();
+        for name in services.services.keys() {
+            if used.contains(name.as_str()) {
+                continue;
+            }
+            diags.push(Diagnostic {
+                line: None,
+                severity: if strict {
+                    Severity::Error
+                } else {
+                    Severity::Warning
+                },
+                code: "unused-service",
+                pattern: None,
+                message: format!(
+                    "Service '{}' is declared in {} but no rule maps to it.",
+                    name,
+                    services_path.display()
+                ),
+            });
+        }
+    }
+    for (svc, field, problem) in lint_links(services, online) {
+        diags.push(Diagnostic {
+            line: None,
+            severity: Severity::Warning,
+            code: "link",
+            pattern: None,
+            message: format!("Service '{}' {}: {}.", svc, field, problem),
+        });
+    }
+    diags
+}
+
+/// The `unused-pattern` warning for a rule `unused_rules` reported.
+fn unused_pattern(mapper: &ServiceMapper, idx: usize) -> Diagnostic {
+    Diagnostic {
+        line: Some(mapper.lines[idx]),
+        severity: Severity::Warning,
+        code: "unused-pattern",
+        pattern: Some(mapper.patterns[idx].clone()),
+        message: format!(
+            "Pattern '{}' (line {}) matches no files.",
+            mapper.patterns[idx], mapper.lines[idx]
+        ),
+    }
+}
+
+/// Link problems per `(service, field, problem)`; `online` also requests http(s) links.
+fn lint_links(
+    services: &ServicesFile,
+    online: Option<HttpOptions>,
+) -> Vec<(String, &'static str, String)> {
+    let mut problems = Vec::new();
+    let mut to_fetch = Vec::new();
+    for (name, def) in &services.services {
+        for (field, value) in def.links() {
+            match check_link(value) {
+                Some(problem) => problems.push((name.clone(), field, problem)),
+                None if online.is_some() && value.contains("://") => {
+                    to_fetch.push((name.clone(), field, value.to_string()))
+                }
+                None => {}
+            }
+        }
+    }
+    let Some(http) = online.filter(|_| !to_fetch.is_empty()) else {
+        return problems;
+    };
+
+    let client = match http.client() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Skipping link checks: {:#}", e);
+            return problems;
+        }
+    };
+    for chunk in to_fetch.chunks(http.concurrency) {
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let handles: Vec<_> = chunk
+                .iter()
+                .map(|(_, _, url)| scope.spawn(|| probe_link(&client, url)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().ok().flatten())
+                .collect()
+        });
+        for ((name, field, _), problem) in chunk.iter().zip(results) {
+            if let Some(problem) = problem {
+                problems.push((name.clone(), *field, problem));
+            }
+        }
+    }
+    problems.sort_by(|a, b| a.0.cmp(&b.0).then_with(|| a.1.cmp(b.1)));
+    problems
+}
+
+/// HEAD a URL, falling back to GET for servers that refuse HEAD.
+fn probe_link(client: &reqwest::blocking::Client, url: &str) -> Option<String> {
+    let resp = client
+        .head(url)
+        .send()
+        .and_then(|r| match r.status().as_u16() {
+            405 | 501 => client.get(url).send(),
+            _ => Ok(r),
+        });
+    match resp {
+        Ok(r) if r.status().is_client_error() || r.status().is_server_error() => {
+            Some(format!("'{}' returned HTTP {}", url, r.status().as_u16()))
+        }
+        Ok(_) => None,
+        Err(e) => Some(format!("'{}' could not be reached ({})", url, e)),
+    }
+}
+
+/// Services to display under `--max-services`: with more than `max`, the top
+/// `max` by file count (ties by name) plus a summary line; otherwise all of them.
+fn rollup_services(
+    sorted: &[String],
+    files: &BTreeMap<String, Vec<String>>,
+    max: Option<usize>,
+) -> (Vec<String>, Option<String>) {
+    match max {
+        Some(max) if sorted.len() > max => {
+            let mut top = sorted.to_vec();
+            top.sort_by(|a, b| files[b].len().cmp(&files[a].len()).then_with(|| a.cmp(b)));
+            top.truncate(max);
+            let note = format!(
+                "Touches {} services; showing the top {} by file count",
+                sorted.len(),
+                max
+            );
+            (top, Some(note))
+        }
+        _ => (sorted.to_vec(), None),
+    }
+}
+
+fn has_owners(def: Option<&ServiceDef>) -> bool {
+    def.and_then(|d| d.owners.as_ref())
+        .is_some_and(|owners| !owners.is_empty())
+}
+
+/// Canonical service name, resolving services.yaml aliases when available.
+fn canonical<'a>(services: Option<&'a ServicesFile>, svc: &'a str) -> &'a str {
+    match services {
+        Some(s) => s.canonical_name(svc),
+        None => svc,
+    }
+}
+
+/// Impacted services marked deprecated in services.yaml, with their replacement.
+fn deprecated_services<S: AsRef<str>>(
+    impacted: &[S],
+    services: Option<&ServicesFile>,
+) -> Vec<(String, Option<String>)> {
+    let Some(services) = services else {
+        return Vec::new();
+    };
+    impacted
+        .iter()
+        .filter_map(|svc| {
+            let def = services.get(svc.as_ref())?;
+            def.is_deprecated()
+                .then(|| (svc.as_ref().to_string(), def.deprecated_in_favor_of.clone()))
+        })
+        .collect()
+}
+
+fn deprecation_message(svc: &str, replacement: Option<&str>) -> String {
+    match replacement {
+        Some(r) => format!("service '{}' is deprecated; use '{}' instead", svc, r),
+        None => format!("service '{}' is deprecated", svc),
+    }
+}
+
+/// Reads a baseline of known unmapped paths, one per line. Blank lines and
+/// `#` comments are ignored.
+fn read_unmapped_baseline(path: &Path) -> Result<HashSet<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read baseline at {:?}", path))?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+fn write_unmapped_baseline(path: &Path, unmapped: &[String]) -> Result<()> {
+    let mut sorted: Vec<&String> = unmapped.iter().collect();
+    sorted.sort();
+    sorted.dedup();
+    let mut out =
+        String::from("# Known unmapped files (generated by sowners impacted --write-baseline)\n");
+    for f in sorted {
+        out.push_str(f);
+        out.push('\n');
+    }
+    std::fs::write(path, out).with_context(|| format!("Failed to write baseline at {:?}", path))
+}
+
+/// Clusters unmapped files by top-level directory, largest group first.
+/// Files at the repo root are grouped under `./`.
+fn group_unmapped_files(files: &[String]) -> Vec<(String, usize)> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for f in files {
+        let dir = match f.split_once('/') {
+            Some((top, _)) => format!("{}/", top),
+            None => "./".to_string(),
+        };
+        *counts.entry(dir).or_default() += 1;
+    }
+    let mut groups: Vec<(String, usize)> = counts.into_iter().collect();
+    groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    groups
+}
+
+/// Lint findings: printed as they're found in text mode, collected for
+/// `--format json`.
+struct LintReport {
+    json: bool,
+    warnings: Vec<Diagnostic>,
+    errors: Vec<Diagnostic>,
+    /// `(pattern, line)` of rules deleted by `--fix`
+    removed: Vec<(String, usize)>,
+}
+
+impl LintReport {
+    fn push(&mut self, diag: Diagnostic) {
+        if !self.json {
+            println!("{}: {}", diag.severity, diag.message);
+        }
+        match diag.severity {
+            Severity::Error => self.errors.push(diag),
+            Severity::Warning => {
+                note_warning();
+                self.warnings.push(diag);
+            }
+        }
+    }
+
+    fn print(&self, compact: bool) -> Result<()> {
+        let removed = self
+            .removed
+            .iter()
+            .map(|(pattern, line)| RemovedRule {
+                pattern: pattern.clone(),
+                line: *line,
+            })
+            .collect::<Vec<_>>();
+        let payload = LintResult {
+            schema_version: JSON_SCHEMA_VERSION,
+            valid: self.errors.is_empty(),
+            warnings: self.warnings.clone(),
+            errors: self.errors.clone(),
+            removed: (!removed.is_empty()).then_some(removed),
+        };
+        print_json(&payload, compact)
+    }
+}
+
+/// `lint --fix` needs a SERVICEOWNERS file on disk and no `--scope`.
+fn check_fix_allowed(cli: &Cli) -> Result<()> {
+    if cli.serviceowners_content.is_some() {
+        anyhow::bail!("--fix rewrites a SERVICEOWNERS file; it can't fix --serviceowners-content");
+    }
+    if let Some(scope) = &cli.scope {
+        anyhow::bail!(
+            "--fix can't run under --scope {}: rules outside the scope would look unused",
+            scope
+        );
+    }
+    Ok(())
+}
+
+/// `content` without the given 1-based lines; every other byte, including
+/// comments, blank lines and line endings, is kept as is.
+fn remove_lines(content: &str, dead: &HashSet<usize>) -> String {
+    content
+        .split_inclusive('\n')
+        .enumerate()
+        .filter(|(idx, _)| !dead.contains(&(idx + 1)))
+        .map(|(_, line)| line)
+        .collect()
+}
+
+/// Walks the repo and returns the indices of rules that match no file, in
+/// file order. Stops early once every rule has matched something.
+fn unused_rules(mapper: &ServiceMapper, quiet: bool, skip_binary: bool) -> Vec<usize> {
+    let total = mapper.len();
+    let used: Vec<AtomicBool> = (0..total).map(|_| AtomicBool::new(false)).collect();
+    let used_count = AtomicUsize::new(0);
+    let progress = walk_progress(quiet, format!("0/{} patterns used", total));
+    walk_repo_parallel(&progress, skip_binary, || {
+        let (used, used_count, progress) = (&used, &used_count, &progress);
+        let mut matches = Vec::new();
+        move |path: &str| {
+            // Before exclusions: a rule whose matches are all excluded isn't dead
+            mapper.glob_matches_into(path, &mut matches);
+            let mut newly_used = 0;
+            for &idx in &matches {
+                if !used[idx].swap(true, Ordering::Relaxed) {
+                    newly_used += 1;
+                }
+            }
+            if newly_used == 0 {
+                return used_count.load(Ordering::Relaxed) < total;
+            }
+            let count = used_count.fetch_add(newly_used, Ordering::Relaxed) + newly_used;
+            progress.set_message(format!("{}/{} patterns used", count, total));
+            count < total
+        }
+    });
+    progress.finish_and_clear();
+    // In rule order, whatever order the walk went
+    (0..total)
+        .filter(|&idx| !used[idx].load(Ordering::Relaxed))
+        .collect()
+}
+
+/// Standalone HTML ownership dashboard with inline CSS and a search box.
+fn render_html_report(
+    mapper: &ServiceMapper,
+    services: Option<&ServicesFile>,
+    file_counts: &HashMap<String, usize>,
+    unmapped: usize,
+    ignored: usize,
+) -> String {
+    let mut rule_counts: HashMap<&str, usize> = HashMap::new();
+    for svc in mapper.service_names() {
+        *rule_counts.entry(canonical(services, svc)).or_default() += 1;
+    }
+    let mut names: Vec<&str> = rule_counts.keys().copied().collect();
+    if let Some(services) = services {
+        names.extend(services.services.keys().map(String::as_str));
+    }
+    names.sort();
+    names.dedup();
+
+    let mut rows = String::new();
+    for name in &names {
+        let def = services.and_then(|s| s.get(name));
+        let contact = def
+            .and_then(|d| d.contact.as_ref())
+            .map(|c| {
+                c.entries()
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, html_escape(v)))
+                    .collect::<Vec<_>>()
+                    .join("<br>")
+            })
+            .unwrap_or_default();
+        let link = |url: Option<&String>, label: &str| {
+            url.map(|u| format!("<a href=\"{}\">{}</a>", html_escape(u), label))
+                .unwrap_or_default()
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{} {}</td><td class=\"n\">{}</td><td class=\"n\">{}</td></tr>\n",
+            html_escape(name),
+            html_escape(&def.map(owners_cell).unwrap_or_default()),
+            contact,
+            link(def.and_then(|d| d.docs.as_ref()), "docs"),
+            link(def.and_then(|d| d.runbook.as_ref()), "runbook"),
+            rule_counts.get(name).copied().unwrap_or(0),
+            file_counts.get(*name).copied().unwrap_or(0),
+        ));
+    }
+
+    let mapped: usize = file_counts.values().sum();
+    format!(
+        r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Service ownership</title>
+<style>
+body {{ font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; margin: 2rem; color: #1f2328; }}
+h1 {{ font-size: 1.5rem; }}
+.summary {{ color: #59636e; margin-bottom: 1rem; }}
+input {{ padding: .4rem .6rem; width: 20rem; margin-bottom: 1rem; border: 1px solid #d1d9e0; border-radius: 6px; }}
+table {{ border-collapse: collapse; width: 100%; }}
+th, td {{ text-align: left; padding: .4rem .6rem; border-bottom: 1px solid #d1d9e0; vertical-align: top; }}
+th {{ background: #f6f8fa; }}
+td.n {{ text-align: right; font-variant-numeric: tabular-nums; }}
+</style>
+</head>
+<body>
+<h1>Service ownership</h1>
+<div class="summary">{services} services · {rules} rules · {mapped} mapped files · {unmapped} unmapped files · {ignored} ignored files</div>
+<input id="q" type="search" placeholder="Filter services, owners, contacts…" autofocus>
+<table>
+<thead><tr><th>Service</th><th>Owners</th><th>Contact</th><th>Links</th><th>Rules</th><th>Files</th></tr></thead>
+<tbody id="rows">
+{rows}</tbody>
+</table>
+<script>
+document.getElementById("q").addEventListener("input", function (e) {{
+  var q = e.target.value.toLowerCase();
+  document.querySelectorAll("#rows tr").forEach(function (tr) {{
+    tr.style.display = tr.textContent.toLowerCase().includes(q) ? "" : "none";
+  }});
+}});
+</script>
+</body>
+</html>
+"##,
+        services = names.len(),
+        rules = mapper.len(),
+        mapped = mapped,
+        unmapped = unmapped,
+        ignored = ignored,
+        rows = rows,
+    )
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Files per service across the whole repo, plus the number of unmapped and
+/// ignored (unmapped but listed in the ignore file) files.
+fn ownership_counts(
+    mapper: &ServiceMapper,
+    ignore: &IgnoreRules,
+    quiet: bool,
+    skip_binary: bool,
+) -> (HashMap<String, usize>, usize, usize) {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut unmapped = 0;
+    let mut ignored = 0;
+    let progress = walk_progress(quiet, "counting files");
+    walk_repo(&progress, skip_binary, |path| {
+        match mapper.find_service(path) {
+            Some(svc) => *counts.entry(svc.to_string()).or_default() += 1,
+            None if ignore.is_ignored(path) => ignored += 1,
+            None => unmapped += 1,
+        }
+        true
+    });
+    progress.finish_and_clear();
+    (counts, unmapped, ignored)
+}
+
+/// Number of repo files each rule is the winning match for.
+fn rule_usage(mapper: &ServiceMapper, quiet: bool, skip_binary: bool) -> Vec<usize> {
+    let mut usage = vec![0; mapper.len()];
+    let progress = walk_progress(quiet, "counting matches");
+    walk_repo(&progress, skip_binary, |path| {
+        if let Some(idx) = mapper.winning_rule(path) {
+            usage[idx] += 1;
+        }
+        true
+    });
+    progress.finish_and_clear();
+    usage
+}
+
+/// One contact row per service, flagging ones without a contact or declaration.
+fn print_contacts(
+    mapper: &ServiceMapper,
+    services: Option<&ServicesFile>,
+    format: ContactsFormat,
+    compact: bool,
+) -> Result<()> {
+    let mut names: Vec<String> = mapper
+        .service_names()
+        .iter()
+        .map(|svc| canonical(services, svc).to_string())
+        .collect();
+    if let Some(services) = services {
+        names.extend(services.services.keys().cloned());
+    }
+    names.sort();
+    names.dedup();
+
+    let rows: Vec<(&str, Option<&ServiceDef>)> = names
+        .iter()
+        .map(|name| (name.as_str(), services.and_then(|s| s.services.get(name))))
+        .collect();
+    let missing_contact = |def: &ServiceDef| {
+        def.contact
+            .as_ref()
+            .map(|c| c.entries().is_empty())
+            .unwrap_or(true)
+    };
+
+    if format == ContactsFormat::Json {
+        let out: Vec<ContactRow> = rows
+            .iter()
+            .map(|(name, def)| ContactRow::new(name, *def))
+            .collect();
+        return print_json(&out, compact);
+    }
+
+    let cell = |v: Option<&str>| v.unwrap_or("-").to_string();
+    let mut table = vec![[
+        "SERVICE".to_string(),
+        "SLACK".to_string(),
+        "EMAIL".to_string(),
+        "DOCS".to_string(),
+        "RUNBOOK".to_string(),
+        "NOTE".to_string(),
+    ]];
+    for (name, def) in &rows {
+        let contact = def.and_then(|d| d.contact.as_ref());
+        let note = match def {
+            None => "not in services.yaml",
+            Some(def) if missing_contact(def) => "missing contact",
+            Some(_) => "",
+        };
+        table.push([
+            name.to_string(),
+            cell(contact.and_then(|c| c.slack.as_deref())),
+            cell(contact.and_then(|c| c.email.as_deref())),
+            cell(def.and_then(|d| d.docs.as_deref())),
+            cell(def.and_then(|d| d.runbook.as_deref())),
+            note.to_string(),
+        ]);
+    }
+    let mut widths = [0usize; 6];
+    for row in &table {
+        for (w, value) in widths.iter_mut().zip(row) {
+            *w = (*w).max(value.chars().count());
+        }
+    }
+    for row in &table {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(value, w)| format!("{:<w$}", value, w = w))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+    Ok(())
+}
+
+/// How many entries the "top" lists in `stats` show.
+const STATS_TOP_N: usize = 5;
+
+fn print_stats(
+    mapper: &ServiceMapper,
+    usage: Option<&[usize]>,
+    format: TextOrJson,
+    compact: bool,
+) -> Result<()> {
+    let total = mapper.len();
+    let (mut recursive, mut literal) = (0, 0);
+    for idx in 0..total {
+        let glob = mapper.normalized_pattern(idx);
+        if glob.contains("**") {
+            recursive += 1;
+        } else if !glob.contains(['*', '?', '[', '{']) {
+            literal += 1;
+        }
+    }
+    let wildcard = total - recursive - literal;
+    let avg_specificity = if total == 0 {
+        0.0
+    } else {
+        (0..total).map(|idx| mapper.specificity(idx)).sum::<usize>() as f64 / total as f64
+    };
+
+    let mut rules_per_service: HashMap<&str, usize> = HashMap::new();
+    for svc in mapper.service_names() {
+        *rules_per_service.entry(svc).or_default() += 1;
+    }
+    let mut top_services: Vec<(&str, usize)> =
+        rules_per_service.iter().map(|(s, n)| (*s, *n)).collect();
+    top_services.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+    top_services.truncate(STATS_TOP_N);
+
+    let (most_used, unused) = match usage {
+        Some(usage) => {
+            let mut used: Vec<usize> = (0..total).filter(|&idx| usage[idx] > 0).collect();
+            used.sort_by(|&a, &b| usage[b].cmp(&usage[a]).then_with(|| a.cmp(&b)));
+            used.truncate(STATS_TOP_N);
+            let unused: Vec<usize> = (0..total).filter(|&idx| usage[idx] == 0).collect();
+            (used, unused)
+        }
+        None => (Vec::new(), Vec::new()),
+    };
+    if format == TextOrJson::Json {
+        let rule_usage = |rules: &[usize]| {
+            usage.map(|usage| {
+                rules
+                    .iter()
+                    .map(|&idx| RuleUsage {
+                        pattern: mapper.patterns[idx].clone(),
+                        service: mapper.service_names()[idx].clone(),
+                        line: mapper.lines[idx],
+                        files: usage[idx],
+                    })
+                    .collect()
+            })
+        };
+        let payload = StatsReport {
+            schema_version: JSON_SCHEMA_VERSION,
+            rules: total,
+            services: rules_per_service.len(),
+            patterns: PatternCounts {
+                recursive,
+                literal,
+                wildcard,
+            },
+            average_specificity: avg_specificity,
+            top_services: top_services
+                .iter()
+                .map(|(svc, n)| ServiceRules {
+                    service: svc.to_string(),
+                    rules: *n,
+                })
+                .collect(),
+            most_used_rules: rule_usage(&most_used),
+            unused_rules: rule_usage(&unused),
+        };
+        return print_json(&payload, compact);
+    }
+
+    println!("Rules: {}", total);
+    println!("Services: {}", rules_per_service.len());
+    println!(
+        "Patterns: {} recursive (**), {} literal, {} other wildcard",
+        recursive, literal, wildcard
+    );
+    println!("Average specificity: {:.1}", avg_specificity);
+    if !top_services.is_empty() {
+        println!("\nServices with the most rules:");
+        for (svc, n) in &top_services {
+            println!("- {} ({})", svc, n);
+        }
+    }
+    if let Some(usage) = usage {
+        println!("\nMost-used rules:");
+        for &idx in &most_used {
+            println!(
+                "- {} -> {} (line {}): {} files",
+                mapper.patterns[idx],
+                mapper.service_names()[idx],
+                mapper.lines[idx],
+                usage[idx]
+            );
+        }
+        println!("\nUnused rules: {}", unused.len());
+        for &idx in &unused {
+            println!(
+                "- {} -> {} (line {})",
+                mapper.patterns[idx],
+                mapper.service_names()[idx],
+                mapper.lines[idx]
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Visits every non-ignored file until the visitor returns `false`.
+fn walk_repo(progress: &ProgressBar, skip_binary: bool, visit: impl FnMut(&str) -> bool) {
+    walk_dir(Path::new("."), progress, skip_binary, visit)
+}
+
+/// `walk_repo` from `root`, with paths relative to it.
+fn walk_dir(
+    root: &Path,
+    progress: &ProgressBar,
+    skip_binary: bool,
+    mut visit: impl FnMut(&str) -> bool,
+) {
+    let (mut visited, mut skipped) = (0usize, 0usize);
+    for result in ignore::WalkBuilder::new(root).build() {
+        match result {
+            Ok(entry) => {
+                if entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                    if skip_binary && is_binary_file(entry.path()) {
+                        log::trace!("Skipping binary file {:?}", entry.path());
+                        skipped += 1;
+                        continue;
+                    }
+                    if let Ok(rel) = entry.path().strip_prefix(root) {
+                        let keep_going = visit(&rel.to_string_lossy());
+                        visited += 1;
+                        progress.inc(1);
+                        if !keep_going {
+                            log::info!("Stopping walk early after {} files", visited);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(err) => progress.suspend(|| eprintln!("Error walking repo: {}", err)),
+        }
+    }
+    log::info!(
+        "Walked {} files ({} binary files skipped)",
+        visited,
+        skipped
+    );
+}
+
+/// `walk_repo` across worker threads, one visitor per thread, in no particular order.
+fn walk_repo_parallel<F, V>(progress: &ProgressBar, skip_binary: bool, make_visitor: F)
+where
+    F: Fn() -> V + Sync,
+    V: FnMut(&str) -> bool + Send,
+{
+    let (visited, skipped) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    ignore::WalkBuilder::new(".").build_parallel().run(|| {
+        let mut visit = make_visitor();
+        let (visited, skipped) = (&visited, &skipped);
+        Box::new(move |result| {
+            let entry = match result {
+                Ok(entry) => entry,
+                Err(err) => {
+                    progress.suspend(|| eprintln!("Error walking repo: {}", err));
+                    return ignore::WalkState::Continue;
+                }
+            };
+            if !entry.file_type().map(|ft| ft.is_file()).unwrap_or(false) {
+                return ignore::WalkState::Continue;
+            }
+            if skip_binary && is_binary_file(entry.path()) {
+                log::trace!("Skipping binary file {:?}", entry.path());
+                skipped.fetch_add(1, Ordering::Relaxed);
+                return ignore::WalkState::Continue;
+            }
+            let Ok(rel) = entry.path().strip_prefix(".") else {
+                return ignore::WalkState::Continue;
+            };
+            let keep_going = visit(&rel.to_string_lossy());
+            visited.fetch_add(1, Ordering::Relaxed);
+            progress.inc(1);
+            if keep_going {
+                ignore::WalkState::Continue
+            } else {
+                ignore::WalkState::Quit
+            }
+        })
+    });
+    log::info!(
+        "Walked {} files ({} binary files skipped)",
+        visited.into_inner(),
+        skipped.into_inner()
+    );
+}
+
+/// Extensions that are always treated as binary without reading the file.
+const BINARY_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "bmp", "ico", "webp", "tiff", "psd", "pdf", "zip", "gz", "tgz",
+    "bz2", "xz", "7z", "rar", "jar", "war", "woff", "woff2", "ttf", "otf", "eot", "mp3", "mp4",
+    "wav", "mov", "avi", "so", "dll", "dylib", "exe", "class", "o", "a", "wasm",
+];
+
+/// Binary detection: a known binary extension, otherwise a NUL byte in the
+/// first 8 KiB (the same heuristic git uses).
+fn is_binary_file(path: &Path) -> bool {
+    use std::io::Read;
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        if BINARY_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()) {
+            return true;
+        }
+    }
+    let mut buf = [0u8; 8192];
+    match std::fs::File::open(path).and_then(|mut f| f.read(&mut buf)) {
+        Ok(n) => buf[..n].contains(&0),
+        Err(_) => false,
+    }
+}
+
+/// Spinner for long repo walks, showing `message` after the file count until
+/// the caller replaces it. Hidden under `--quiet` or when stderr is not a TTY.
+fn walk_progress(quiet: bool, message: impl Into<Cow<'static, str>>) -> ProgressBar {
+    if quiet || !std::io::stderr().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::with_template("{spinner} {pos} files scanned, {msg}")
+            .unwrap_or_else(|_| ProgressStyle::default_spinner()),
+    );
+    pb.set_message(message);
+    pb.enable_steady_tick(Duration::from_millis(100));
+    pb
+}
+
+/// Per-commit breakdown of impacted services for `impacted --commits`;
+/// `services_of` gives the sorted services a commit touches.
+fn impacted_by_commit(
+    diff: Option<&str>,
+    services_of: impl Fn(&str) -> Result<Vec<String>>,
+    format: ImpactedFormat,
+    compact: bool,
+    no_header: bool,
+) -> Result<()> {
+    let mut rows = Vec::new();
+    for (sha, subject) in commits_in_range(diff)? {
+        let impacted = services_of(&sha)?;
+        rows.push((sha, subject, impacted));
+    }
+
+    match format {
+        ImpactedFormat::Json => {
+            let payload: Vec<CommitImpact> = rows
+                .into_iter()
+                .map(|(sha, _, services)| CommitImpact { sha, services })
+                .collect();
+            print_json(&payload, compact)?;
+        }
+        ImpactedFormat::Markdown | ImpactedFormat::GithubMarkdown => {
+            println!("### Impacted Services by Commit\n");
+            println!("| Commit | Subject | Services |");
+            println!("| --- | --- | --- |");
+            for (sha, subject, services) in &rows {
+                println!(
+                    "| `{}` | {} | {} |",
+                    short_sha(sha),
+                    markdown_escape(subject),
+                    services.join(", ")
+                );
+            }
+        }
+        ImpactedFormat::Tsv => {
+            if !no_header {
+                println!("sha\tservice");
+            }
+            for (sha, _, services) in &rows {
+                for svc in services {
+                    println!("{}\t{}", sha, svc);
+                }
+            }
+        }
+        ImpactedFormat::Text | ImpactedFormat::Changelog => {
+            for (sha, subject, services) in &rows {
+                println!("{} {}", short_sha(sha), subject);
+                if services.is_empty() {
+                    println!("  (no services)");
+                }
+                for svc in services {
+                    println!("  - {}", svc);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn short_sha(sha: &str) -> &str {
+    &sha[..sha.len().min(7)]
+}
+
+/// Fails unless `rev` names a commit (a tag, branch or sha); `flag` is the
+/// option it came from, for the error.
+fn verify_commit(flag: &str, rev: &str) -> Result<()> {
+    let status = Command::new("git")
+        .args([
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{}^{{commit}}", rev),
+        ])
+        .stdout(std::process::Stdio::null())
+        .status()
+        .context("Failed to run git rev-parse")?;
+    if !status.success() {
+        anyhow::bail!("{} '{}' does not resolve to a commit", flag, rev);
+    }
+    Ok(())
+}
+
+/// `(sha, subject)` for each commit in the range, oldest first. A symmetric
+/// `A...B` range is read as `A..B`: only commits reachable from B count.
+fn commits_in_range(diff_arg: Option<&str>) -> Result<Vec<(String, String)>> {
+    let range = resolve_diff_range(diff_arg).replace("...", "..");
+    let output = Command::new("git")
+        .args(["log", "--reverse", "--format=%H%x09%s", &range])
+        .output()
+        .context("Failed to run git log")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git log failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout
+        .lines()
+        .filter_map(|l| {
+            let (sha, subject) = l.split_once('\t').unwrap_or((l, ""));
+            (!sha.is_empty()).then(|| (sha.to_string(), subject.to_string()))
+        })
+        .collect())
+}
+
+/// Files changed by a single commit, against its first parent for merges,
+/// filtered like `get_changed_entries`.
+fn commit_changed_files(sha: &str, opts: DiffOptions) -> Result<Vec<String>> {
+    let fields = git_paths(&[
+        "diff-tree",
+        "--no-commit-id",
+        "--raw",
+        "-z",
+        "-r",
+        "-M",
+        "--root",
+        "--diff-merges=first-parent",
+        opts.diff_filter(),
+        sha,
+    ])?;
+    Ok(parse_raw_diff(fields, opts, None)
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// Which changes in a diff range count as changed files
+#[derive(Clone, Copy, Default)]
+struct DiffOptions {
+    /// Keep submodule pointer updates
+    include_submodules: bool,
+    /// Drop files whose changes are all whitespace
+    ignore_whitespace: bool,
+    /// Keep deleted files
+    include_deleted: bool,
+}
+
+impl DiffOptions {
+    fn diff_filter(self) -> &'static str {
+        if self.include_deleted {
+            "--diff-filter=ACDMR"
+        } else {
+            "--diff-filter=ACMR"
+        }
+    }
+}
+
+/// Paths changed in the range (see `get_changed_entries`).
+fn get_changed_files(diff_arg: Option<&str>, opts: DiffOptions) -> Result<Vec<String>> {
+    Ok(get_changed_entries(diff_arg, opts)?
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect())
+}
+
+/// Files changed in the range, with how they changed; a rename lists the old
+/// path and then the new one.
+fn get_changed_entries(
+    diff_arg: Option<&str>,
+    opts: DiffOptions,
+) -> Result<Vec<(String, FileStatus)>> {
+    let range = resolve_diff_range(diff_arg);
+    let fields = git_paths(&["diff", "--raw", "-z", opts.diff_filter(), &range])?;
+    let non_whitespace = if opts.ignore_whitespace {
+        Some(non_whitespace_changes(&range, opts)?)
+    } else {
+        None
+    };
+    Ok(parse_raw_diff(fields, opts, non_whitespace.as_ref()))
+}
+
+/// Changed files from `git diff --raw -z` fields, filtered by `opts`.
+fn parse_raw_diff(
+    fields: Vec<String>,
+    opts: DiffOptions,
+    non_whitespace: Option<&HashSet<String>>,
+) -> Vec<(String, FileStatus)> {
+    let mut files = Vec::new();
+    let mut fields = fields.into_iter();
+    while let Some(meta) = fields.next() {
+        let mut path = fields.next();
+        let status = meta.rsplit(' ').next().unwrap_or_default();
+        let mut renamed_from = None;
+        if status.starts_with(['R', 'C']) {
+            if status.starts_with('R') {
+                renamed_from = path.take();
+            }
+            path = fields.next();
+        }
+        let Some(path) = path else {
+            break;
+        };
+        let file_status = match status.chars().next() {
+            Some('A') => FileStatus::Added,
+            Some('D') => FileStatus::Deleted,
+            Some('R') => FileStatus::Renamed,
+            Some('C') => FileStatus::Copied,
+            _ => FileStatus::Modified,
+        };
+        let gitlink = meta
+            .trim_start_matches(':')
+            .split(' ')
+            .take(2)
+            .any(|mode| mode == "160000");
+        if gitlink && !opts.include_submodules {
+            log::debug!("{}: submodule update, skipped", path);
+            continue;
+        }
+        if non_whitespace.is_some_and(|kept| !kept.contains(&path)) {
+            log::debug!("{}: whitespace-only change, skipped", path);
+            continue;
+        }
+        if let Some(old) = renamed_from {
+            files.push((old, FileStatus::RenamedFrom));
+        }
+        files.push((path, file_status));
+    }
+    files
+}
+
+/// Paths `git diff -w` still reports in the range.
+fn non_whitespace_changes(range: &str, opts: DiffOptions) -> Result<HashSet<String>> {
+    let fields = git_paths(&["diff", "--numstat", "-z", "-w", opts.diff_filter(), range])?;
+    Ok(parse_numstat(fields))
+}
+
+/// Paths from `git diff --numstat -z` fields; a rename keeps the new path.
+fn parse_numstat(fields: Vec<String>) -> HashSet<String> {
+    let mut paths = HashSet::new();
+    let mut fields = fields.into_iter();
+    while let Some(row) = fields.next() {
+        let path = match row.splitn(3, '\t').nth(2) {
+            Some("") | None => {
+                fields.next();
+                fields.next()
+            }
+            Some(path) => Some(path.to_string()),
+        };
+        paths.extend(path);
+    }
+    paths
+}
+
+/// The diff range to use when `--diff` is omitted: the PR range on CI, else the
+/// last commit.
+fn resolve_diff_range(explicit: Option<&str>) -> String {
+    if let Some(range) = explicit {
+        return range.to_string();
+    }
+    let event_base = std::env::var("GITHUB_EVENT_PATH").ok().and_then(|path| {
+        let event = read_event_payload(Path::new(&path)).ok()?;
+        let sha = event
+            .get("pull_request")?
+            .get("base")?
+            .get("sha")?
+            .as_str()?;
+        Some(sha.to_string())
+    });
+    let range = match event_base {
+        Some(sha) => format!("{}...HEAD", sha),
+        None => match std::env::var("GITHUB_BASE_REF")
+            .or_else(|_| std::env::var("BITBUCKET_PR_DESTINATION_BRANCH"))
+        {
+            Ok(base) if !base.is_empty() => format!("origin/{}...HEAD", base),
+            _ => "HEAD~1...HEAD".to_string(),
+        },
+    };
+    log::info!("Using diff range {}", range);
+    range
+}
+
+/// Files with uncommitted changes, from `git status` (see `parse_status`).
+fn working_tree_changes(opts: DiffOptions) -> Result<Vec<String>> {
+    let entries = git_paths(&["status", "--porcelain", "-z", "--untracked-files=all"])?;
+    Ok(parse_status(entries, opts))
+}
+
+/// Paths from `git status --porcelain -z` entries (see `working_tree_changes`).
+fn parse_status(entries: Vec<String>, opts: DiffOptions) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut entries = entries.into_iter();
+    while let Some(entry) = entries.next() {
+        // "XY path"; renames and copies are followed by the original path
+        let Some((status, path)) = entry.split_at_checked(3) else {
+            continue;
+        };
+        if status.starts_with('R') || status.starts_with('C') {
+            entries.next();
+        }
+        if status.contains('D') && !opts.include_deleted {
+            log::debug!("{}: deleted, skipped", path);
+            continue;
+        }
+        files.push(path.to_string());
+    }
+    files
+}
+
+/// Runs a git command that prints NUL-separated paths (`-z`).
+fn git_paths(args: &[&str]) -> Result<Vec<String>> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .with_context(|| format!("Failed to run git {}", args[0]))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("git printed a non-UTF-8 path")?;
+    let paths: Vec<String> = stdout
+        .split('\0')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect();
+    log::info!("git {}: {} paths", args.join(" "), paths.len());
+    Ok(paths)
+}
+
+/// Union of the files changed in each range, in first-seen order. No ranges
+/// means the default range, as for a single `--diff`.
+fn changed_files_in_ranges(
+    ranges: &[String],
+    opts: DiffOptions,
+) -> Result<Vec<(String, FileStatus)>> {
+    if ranges.is_empty() {
+        return get_changed_entries(None, opts);
+    }
+    let mut seen = HashSet::new();
+    let mut files = Vec::new();
+    for range in ranges {
+        for (path, status) in get_changed_entries(Some(range), opts)? {
+            if seen.insert(path.clone()) {
+                files.push((path, status));
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Renames from every range (see `changed_files_in_ranges`).
+fn renames_in_ranges(ranges: &[String]) -> Result<Vec<(String, String)>> {
+    if ranges.is_empty() {
+        return changed_renames(None);
+    }
+    let mut renames = Vec::new();
+    for range in ranges {
+        for rename in changed_renames(Some(range))? {
+            if !renames.contains(&rename) {
+                renames.push(rename);
+            }
+        }
+    }
+    Ok(renames)
+}
+
+/// `(old, new)` for each rename in the range, as detected by `git diff -M`.
+fn changed_renames(diff_arg: Option<&str>) -> Result<Vec<(String, String)>> {
+    let range = resolve_diff_range(diff_arg);
+    let fields = git_paths(&[
+        "diff",
+        "--name-status",
+        "-M",
+        "-z",
+        "--diff-filter=R",
+        &range,
+    ])?;
+    // `-z` output is `R<score>`, old path, new path, repeated
+    Ok(fields
+        .chunks_exact(3)
+        .filter(|chunk| chunk[0].starts_with('R'))
+        .map(|chunk| (chunk[1].clone(), chunk[2].clone()))
+        .collect())
+}
+
+/// Splits renames into same-service and cross-service moves, updating `files`.
+fn split_renames(
+    mapper: &ServiceMapper,
+    services: Option<&ServicesFile>,
+    renames: Vec<(String, String)>,
+    files: &mut Vec<String>,
+) -> (Vec<MoveWithin>, Vec<MoveAcross>) {
+    let service_of = |path: &str| {
+        mapper
+            .find_service(path)
+            .map(|svc| canonical(services, svc).to_string())
+    };
+    let mut within = Vec::new();
+    let mut across = Vec::new();
+    for (from, to) in renames {
+        match (service_of(&from), service_of(&to)) {
+            (Some(a), Some(b)) if a == b => {
+                files.retain(|f| *f != to && *f != from);
+                within.push(MoveWithin {
+                    service: a,
+                    from,
+                    to,
+                });
+            }
+            (from_service, to_service) => {
+                if !files.contains(&from) {
+                    files.push(from.clone());
+                }
+                across.push(MoveAcross {
+                    from,
+                    from_service,
+                    to,
+                    to_service,
+                });
+            }
+        }
+    }
+    (within, across)
+}
+
+/// Label for `TeamBreakdown::no_team` in text and Markdown output
+const NO_TEAM_LABEL: &str = "(no team)";
+
+/// `(team, impact)` per team, by name, then `(None, impact)` for the
+/// services without a team.
+fn team_rows(teams: &TeamBreakdown) -> impl Iterator<Item = (Option<&str>, &TeamImpact)> {
+    teams
+        .teams
+        .iter()
+        .map(|(team, impact)| (Some(team.as_str()), impact))
+        .chain(teams.no_team.iter().map(|impact| (None, impact)))
+}
+
+/// `### Impacted Teams` table for `impacted --group-by team --format markdown`.
+fn print_markdown_teams(teams: &TeamBreakdown) {
+    println!("### Impacted Teams\n");
+    if teams.is_empty() {
+        println!("_No teams impacted_");
+        return;
+    }
+    println!("| Team | Services | Files |");
+    println!("| --- | --- | --- |");
+    for (team, impact) in team_rows(teams) {
+        let team = match team {
+            Some(team) => format!("**{}**", markdown_escape(team)),
+            None => format!("_{}_", NO_TEAM_LABEL),
+        };
+        println!(
+            "| {} | {} | {} |",
+            team,
+            markdown_escape(&impact.services.join(", ")),
+            impact.count
+        );
+    }
+}
+
+/// One line summarizing same-service moves, e.g. "3 files moved within their
+/// service (auth, backend); ownership unchanged."
+fn moved_within_note(moves: &[MoveWithin]) -> Option<String> {
+    if moves.is_empty() {
+        return None;
+    }
+    let mut names: Vec<&str> = moves.iter().map(|m| m.service.as_str()).collect();
+    names.sort();
+    names.dedup();
+    Some(format!(
+        "{} {} moved within {} service ({}); ownership unchanged.",
+        moves.len(),
+        if moves.len() == 1 { "file" } else { "files" },
+        if moves.len() == 1 { "its" } else { "their" },
+        names.join(", ")
+    ))
+}
+
+fn print_markdown_moves(across: &[MoveAcross], within_note: Option<&str>) {
+    if !across.is_empty() {
+        println!("\n### Moved Across Services\n");
+        for m in across {
+            println!(
+                "- `{}` (**{}**) → `{}` (**{}**)",
+                m.from,
+                markdown_escape(m.from_service.as_deref().unwrap_or("unmapped")),
+                m.to,
+                markdown_escape(m.to_service.as_deref().unwrap_or("unmapped"))
+            );
+        }
+    }
+    if let Some(note) = within_note {
+        println!("\n_{}_", note);
+    }
+}
+
+/// `ServiceMapper::impacted` keyed by canonical name, with ignored files split out.
+fn classify_files(
+    mapper: &ServiceMapper,
+    services: Option<&ServicesFile>,
+    ignore: &IgnoreRules,
+    files: &[String],
+) -> ImpactReport {
+    let mut report = mapper.impacted(files);
+    let mut merged: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (svc, detail) in std::mem::take(&mut report.services) {
+        merged
+            .entry(canonical(services, &svc).to_string())
+            .or_default()
+            .extend(detail.files);
+    }
+    report.impacted_services = merged.keys().cloned().collect();
+    report.services = merged
+        .into_iter()
+        .map(|(svc, files)| (svc, ServiceImpact::new(files, None)))
+        .collect();
+    let (ignored, unmapped) = std::mem::take(&mut report.unmapped_files)
+        .into_iter()
+        .partition(|file| ignore.is_ignored(file));
+    report.unmapped_files = unmapped;
+    report.ignored_files = ignored;
+    report
+}
+
+/// The file lists of an `ImpactReport`'s services, for the text formats.
+fn files_by_service(services: BTreeMap<String, ServiceImpact>) -> BTreeMap<String, Vec<String>> {
+    services
+        .into_iter()
+        .map(|(svc, detail)| (svc, detail.files))
+        .collect()
+}
+
+struct ActionOptions<'a> {
+    diff: Option<String>,
+    mapper: ServiceMapper,
+    services: Option<&'a ServicesFile>,
+    ignore: IgnoreRules,
+    comment: bool,
+    fail_on_unmapped: bool,
+    /// Fail the run on any SERVICEOWNERS warning or error from `validate`
+    strict_lint: bool,
+    template: Option<&'a Path>,
+    /// Skip posting when the report is empty; a previous comment is
+    /// replaced with a short "no impact" note instead.
+    skip_empty: bool,
+    fail_on_deprecated: bool,
+    http: HttpOptions,
+    baseline_diff: Option<String>,
+    baseline_services: Option<String>,
+    platform: Platform,
+    diff_opts: DiffOptions,
+    comment_footer: Option<&'a str>,
+}
+
+fn action_runner(opts: ActionOptions<'_>) -> Result<()> {
+    let ActionOptions {
+        diff: diff_arg,
+        mapper,
+        services,
+        ignore,
+        comment,
+        fail_on_unmapped,
+        strict_lint,
+        template,
+        skip_empty,
+        fail_on_deprecated,
+        http,
+        baseline_diff,
+        baseline_services,
+        platform,
+        diff_opts,
+        comment_footer,
+    } = opts;
+
+    let lint_problems = if strict_lint {
+        mapper.validate()
+    } else {
+        Vec::new()
+    };
+    for diag in &lint_problems {
+        println!("::error::{}", diag.message);
+    }
+
+    // 1. Determine diff
+    let diff = resolve_diff_range(diff_arg.as_deref());
+
+    let files = get_changed_files(Some(&diff), diff_opts)?;
+    let ImpactReport {
+        services: impacted_services,
+        unmapped_files,
+        ignored_files,
+        ..
+    } = classify_files(&mapper, services, &ignore, &files);
+    let impacted_services = files_by_service(impacted_services);
+
+    // Services touched before this PR (e.g. by the rest of a stack)
+    let baseline: Option<HashSet<String>> = match (baseline_diff, baseline_services) {
+        (Some(range), _) => match get_changed_files(Some(&range), diff_opts) {
+            Ok(files) => Some(
+                classify_files(&mapper, services, &ignore, &files)
+                    .impacted_services
+                    .into_iter()
+                    .collect(),
+            ),
+            Err(e) => {
+                // Annotations are single-line; git errors often aren't
+                let reason = format!("{:#}", e);
+                note_warning();
+                println!(
+                    "::warning::Could not compute baseline from '{}' ({}); showing all services",
+                    range,
+                    reason.lines().next().unwrap_or_default().trim()
+                );
+                None
+            }
+        },
+        (None, Some(list)) => Some(
+            list.split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(|s| canonical(services, s).to_string())
+                .collect(),
+        ),
+        (None, None) => None,
+    };
+
+    // GITHUB_OUTPUT
+    if let Ok(path) = std::env::var("GITHUB_OUTPUT") {
+        let mut f = std::fs::OpenOptions::new().append(true).open(path)?;
+        use std::io::Write;
+        // Sorted so downstream matrix jobs see a stable order
+        let services_vec: Vec<&String> = impacted_services.keys().collect();
+        let mut unmapped_vec: Vec<&String> = unmapped_files.iter().collect();
+        unmapped_vec.sort();
+        let services_json = serde_json::to_string(&services_vec)?;
+        let unmapped_json = serde_json::to_string(&unmapped_vec)?;
+        let mut ignored_vec: Vec<&String> = ignored_files.iter().collect();
+        ignored_vec.sort();
+        let ignored_json = serde_json::to_string(&ignored_vec)?;
+        writeln!(f, "impacted_services={}", services_json)?;
+        writeln!(f, "unmapped_files={}", unmapped_json)?;
+        writeln!(f, "ignored_files={}", ignored_json)?;
+        if let Some(baseline) = &baseline {
+            let newly: Vec<&&String> = services_vec
+                .iter()
+                .filter(|s| !baseline.contains(s.as_str()))
+                .collect();
+            writeln!(
+                f,
+                "newly_impacted_services={}",
+                serde_json::to_string(&newly)?
+            )?;
+        }
+    }
+
+    let impacted_names: Vec<&String> = impacted_services.keys().collect();
+    let deprecated = deprecated_services(&impacted_names, services);
+    for (svc, replacement) in &deprecated {
+        // Workflow command: shows up as an annotation on the run
+        note_warning();
+        println!(
+            "::warning::{}",
+            deprecation_message(svc, replacement.as_deref())
+        );
+    }
+
+    // Markdown Body
+    let md = match template {
+        Some(path) => render_comment_template(
+            path,
+            &diff,
+            &impacted_services,
+            &unmapped_files,
+            &ignored_files,
+            services,
+            baseline.as_ref(),
+        )?,
+        None => default_comment(
+            &diff,
+            &impacted_services,
+            &unmapped_files,
+            &ignored_files,
+            services,
+            baseline.as_ref(),
+        ),
+    };
+    let footer = comment_footer.map(expand_footer);
+    let md = finish_comment(md, footer.as_deref());
+
+    // GITHUB_STEP_SUMMARY
+    if let Ok(path) = std::env::var("GITHUB_STEP_SUMMARY") {
+        let mut f = std::fs::OpenOptions::new().append(true).open(path)?;
+        use std::io::Write;
+        f.write_all(md.as_bytes())?;
+    }
+
+    // PR Commenting
+    let is_empty = impacted_services.is_empty() && unmapped_files.is_empty();
+    if comment {
+        if let Some(backend) = comment_backend(platform, http.client()?) {
+            if skip_empty && is_empty {
+                let body = finish_comment(NO_IMPACT_COMMENT.to_string(), footer.as_deref());
+                post_pr_comment(backend.as_ref(), &body, false)?;
+            } else {
+                post_pr_comment(backend.as_ref(), &md, true)?;
+            }
+        }
+    }
+
+    if !lint_problems.is_empty() {
+        std::process::exit(2);
+    }
+    if fail_on_unmapped && !unmapped_files.is_empty() {
+        std::process::exit(3);
+    }
+    if fail_on_deprecated && !deprecated.is_empty() {
+        std::process::exit(4);
+    }
+
+    Ok(())
+}
+
+/// Event payloads larger than this are not parsed.
+const MAX_EVENT_PAYLOAD_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Where the sticky report comment lives. `post_pr_comment` holds the
+/// marker logic; backends only talk to their host's API.
+trait CommentBackend {
+    /// Id of the first comment whose body contains `marker`
+    fn find_existing(&self, marker: &str) -> Result<Option<String>>;
+    fn create(&self, body: &str) -> Result<()>;
+    fn update(&self, id: &str, body: &str) -> Result<()>;
+    /// Human-readable target for log lines, e.g. `PR #12`
+    fn target(&self) -> String;
+}
+
+/// Picks the backend for `platform` from the environment. Logs why and
+/// returns `None` when there is nothing to comment on (e.g. a `push` event).
+fn comment_backend(
+    platform: Platform,
+    client: reqwest::blocking::Client,
+) -> Option<Box<dyn CommentBackend>> {
+    let platform = match platform {
+        Platform::Auto if std::env::var_os("BITBUCKET_PR_ID").is_some() => Platform::Bitbucket,
+        Platform::Auto => Platform::Github,
+        other => other,
+    };
+    match platform {
+        Platform::Bitbucket => {
+            BitbucketComments::from_env(client).map(|b| Box::new(b) as Box<dyn CommentBackend>)
+        }
+        _ => GithubComments::from_env(client).map(|g| Box::new(g) as Box<dyn CommentBackend>),
+    }
+}
+
+/// Issue comments on a GitHub pull request
+struct GithubComments {
+    client: reqwest::blocking::Client,
+    token: String,
+    repo: String,
+    pr_num: i64,
+}
+
+impl GithubComments {
+    fn from_env(client: reqwest::blocking::Client) -> Option<Self> {
+        let Ok(token) = std::env::var("GITHUB_TOKEN") else {
+            println!("GITHUB_TOKEN not set; skipping comment");
+            return None;
+        };
+        let Ok(repo) = std::env::var("GITHUB_REPOSITORY") else {
+            println!("GITHUB_REPOSITORY not set; skipping comment");
+            return None;
+        };
+        let Ok(event_path) = std::env::var("GITHUB_EVENT_PATH") else {
+            println!("GITHUB_EVENT_PATH not set; skipping comment");
+            return None;
+        };
+        let event = match read_event_payload(Path::new(&event_path)) {
+            Ok(event) => event,
+            Err(e) => {
+                println!("{:#}; skipping comment", e);
+                return None;
+            }
+        };
+        match event
+            .get("pull_request")
+            .and_then(|pr| pr.get("number"))
+            .and_then(|n| n.as_i64())
+        {
+            Some(pr_num) => Some(Self {
+                client,
+                token,
+                repo,
+                pr_num,
+            }),
+            None => {
+                println!("not a pull_request event; skipping comment");
+                None
+            }
+        }
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::blocking::RequestBuilder {
+        self.client
+            .request(method, url)
+            .header("Authorization", format!("Bearer {}", self.token))
+            .header("User-Agent", "serviceowners-rust")
+    }
+
+    fn comments_url(&self) -> String {
+        format!(
+            "https://api.github.com/repos/{}/issues/{}/comments",
+            self.repo, self.pr_num
+        )
+    }
+}
+
+impl CommentBackend for GithubComments {
+    fn find_existing(&self, marker: &str) -> Result<Option<String>> {
+        let mut url = format!("{}?per_page=100", self.comments_url());
+        loop {
+            let resp = self
+                .request(reqwest::Method::GET, &url)
+                .send()?
+                .error_for_status()?;
+            let next = resp
+                .headers()
+                .get(reqwest::header::LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_page_link);
+            let page = resp
+                .json::<Vec<serde_json::Value>>()
+                .with_context(|| format!("Unexpected response from {}", url))?;
+            let found = page.iter().find_map(|c| {
+                let body = c.get("body")?.as_str()?;
+                if !body.contains(marker) {
+                    return None;
+                }
+                Some(c.get("id")?.as_i64()?.to_string())
+            });
+            match (found, next) {
+                (Some(id), _) => return Ok(Some(id)),
+                (None, Some(next)) => url = next,
+                (None, None) => return Ok(None),
+            }
+        }
+    }
+
+    fn create(&self, body: &str) -> Result<()> {
+        self.request(reqwest::Method::POST, &self.comments_url())
+            .json(&serde_json::json!({ "body": body }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn update(&self, id: &str, body: &str) -> Result<()> {
+        let url = format!(
+            "https://api.github.com/repos/{}/issues/comments/{}",
+            self.repo, id
+        );
+        self.request(reqwest::Method::PATCH, &url)
+            .json(&serde_json::json!({ "body": body }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn target(&self) -> String {
+        format!("PR #{}", self.pr_num)
+    }
+}
+
+/// The `rel="next"` URL of a GitHub `Link` header, if there is one
+fn next_page_link(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|p| p.trim() == "rel=\"next\"")
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// Comments on a Bitbucket Server / Data Center pull request, via the
+/// `/rest/api/1.0` activities and comments endpoints
+struct BitbucketComments {
+    client: reqwest::blocking::Client,
+    token: String,
+    /// `.../rest/api/1.0/projects/{project}/repos/{slug}/pull-requests/{id}`
+    pr_url: String,
+    pr_id: String,
+}
+
+impl BitbucketComments {
+    /// Reads `BITBUCKET_URL` (server base URL), `BITBUCKET_PROJECT`,
+    /// `BITBUCKET_REPO_SLUG`, `BITBUCKET_PR_ID` and `BITBUCKET_TOKEN`.
+    fn from_env(client: reqwest::blocking::Client) -> Option<Self> {
+        let var = |name: &str| match std::env::var(name) {
+            Ok(value) if !value.is_empty() => Some(value),
+            _ => {
+                println!("{} not set; skipping comment", name);
+                None
+            }
+        };
+        let token = var("BITBUCKET_TOKEN")?;
+        let base = var("BITBUCKET_URL")?;
+        let project = var("BITBUCKET_PROJECT")?;
+        let slug = var("BITBUCKET_REPO_SLUG")?;
+        let pr_id = var("BITBUCKET_PR_ID")?;
+        let pr_url = format!(
+            "{}/rest/api/1.0/projects/{}/repos/{}/pull-requests/{}",
+            base.trim_end_matches('/'),
+            project,
+            slug,
+            pr_id
+        );
+        Some(Self {
+            client,
+            token,
+            pr_url,
+            pr_id,
+        })
+    }
+
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::blocking::RequestBuilder {
+        self.client
+            .request(method, url)
+            .header("Authorization", format!("Bearer {}", self.token))
+    }
+
+    fn get_json(&self, url: &str) -> Result<serde_json::Value> {
+        self.request(reqwest::Method::GET, url)
+            .send()?
+            .error_for_status()?
+            .json()
+            .with_context(|| format!("Unexpected response from {}", url))
+    }
+}
+
+impl CommentBackend for BitbucketComments {
+    fn find_existing(&self, marker: &str) -> Result<Option<String>> {
+        let mut start = 0;
+        loop {
+            let url = format!("{}/activities?limit=100&start={}", self.pr_url, start);
+            let page = self.get_json(&url)?;
+            let values = page.get("values").and_then(|v| v.as_array());
+            let found = values.into_iter().flatten().find_map(|activity| {
+                if activity.get("action")?.as_str()? != "COMMENTED" {
+                    return None;
+                }
+                let comment = activity.get("comment")?;
+                if !comment.get("text")?.as_str()?.contains(marker) {
+                    return None;
+                }
+                Some(comment.get("id")?.as_i64()?.to_string())
+            });
+            if found.is_some() {
+                return Ok(found);
+            }
+            let last = page.get("isLastPage").and_then(|v| v.as_bool());
+            match page.get("nextPageStart").and_then(|v| v.as_u64()) {
+                Some(next) if last == Some(false) => start = next,
+                _ => return Ok(None),
+            }
+        }
+    }
+
+    fn create(&self, body: &str) -> Result<()> {
+        self.request(reqwest::Method::POST, &format!("{}/comments", self.pr_url))
+            .json(&serde_json::json!({ "text": body }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn update(&self, id: &str, body: &str) -> Result<()> {
+        // Edits must quote the comment's current version
+        let url = format!("{}/comments/{}", self.pr_url, id);
+        let version = self.get_json(&url)?.get("version").and_then(|v| v.as_i64());
+        self.request(reqwest::Method::PUT, &url)
+            .json(&serde_json::json!({ "text": body, "version": version.unwrap_or(0) }))
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    fn target(&self) -> String {
+        format!("pull request {}", self.pr_id)
+    }
+}
+
+fn read_event_payload(path: &Path) -> Result<serde_json::Value> {
+    let size = std::fs::metadata(path)
+        .with_context(|| format!("Failed to read event payload at {:?}", path))?
+        .len();
+    if size > MAX_EVENT_PAYLOAD_BYTES {
+        anyhow::bail!(
+            "Event payload at {:?} is {} bytes (limit {})",
+            path,
+            size,
+            MAX_EVENT_PAYLOAD_BYTES
+        );
+    }
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read event payload at {:?}", path))?;
+    serde_json::from_str(&content)
+        .with_context(|| format!("Failed to parse event payload at {:?}", path))
+}
+
+/// Built-in Action comment body (without the update markers).
+fn default_comment(
+    diff: &str,
+    impacted_services: &BTreeMap<String, Vec<String>>,
+    unmapped_files: &[String],
+    ignored_files: &[String],
+    services: Option<&ServicesFile>,
+    baseline: Option<&HashSet<String>>,
+) -> String {
+    let mut md = String::new();
+    md.push_str("### 🧭 ServiceOwners Impact Report\n\n");
+    md.push_str(&format!("Diff: `{}`\n\n", diff));
+    if impacted_services.is_empty() {
+        md.push_str("_No services impacted_");
+    } else {
+        let sorted: Vec<&String> = impacted_services.keys().collect();
+        let is_new = |svc: &str| baseline.is_some_and(|b| !b.contains(svc));
+        if baseline.is_some() {
+            let new_count = sorted.iter().filter(|s| is_new(s)).count();
+            md.push_str(&format!(
+                "{} of {} impacted services are **new** compared to the baseline.\n\n",
+                new_count,
+                sorted.len()
+            ));
+        }
+        let name_cell = |svc: &str| {
+            if is_new(svc) {
+                format!("**{}** 🆕", svc)
+            } else {
+                format!("**{}**", svc)
+            }
+        };
+        if let Some(services) = services {
+            md.push_str("| Service | Owners | On-call |\n| --- | --- | --- |\n");
+            for svc in sorted {
+                let def = services.get(svc);
+                md.push_str(&format!(
+                    "| {} | {} | {} |\n",
+                    name_cell(svc),
+                    def.map(owners_cell).unwrap_or_default(),
+                    def.map(oncall_cell).unwrap_or_default()
+                ));
+            }
+        } else {
+            md.push_str("| Service | \n| --- | \n");
+            for svc in sorted {
+                md.push_str(&format!("| {} | \n", name_cell(svc)));
+            }
+        }
+    }
+    let names: Vec<&String> = impacted_services.keys().collect();
+    let deprecated = deprecated_services(&names, services);
+    for (svc, replacement) in &deprecated {
+        md.push_str(&format!(
+            "\n> ⚠️ **Deprecated:** {}\n",
+            deprecation_message(svc, replacement.as_deref())
+        ));
+    }
+    if !unmapped_files.is_empty() {
+        if !md.ends_with('\n') {
+            md.push('\n');
+        }
+        md.push_str("\n### Unmapped Files\n\n");
+        let mut list = String::new();
+        for f in unmapped_files {
+            list.push_str(&format!("- `{}`\n", f));
+        }
+        if unmapped_files.len() > COLLAPSE_UNMAPPED_AFTER {
+            md.push_str(&format!(
+                "<details>\n<summary>{} unmapped files</summary>\n\n{}\n</details>\n",
+                unmapped_files.len(),
+                list
+            ));
+        } else {
+            md.push_str(&list);
+        }
+    }
+    if !ignored_files.is_empty() {
+        if !md.ends_with('\n') {
+            md.push('\n');
+        }
+        md.push_str(&format!("\n{}\n", ignored_note(ignored_files.len())));
+    }
+    md
+}
+
+/// Longer unmapped lists are folded into a `<details>` block.
+const COLLAPSE_UNMAPPED_AFTER: usize = 10;
+
+/// Handlebars context for `render_comment_template`
+#[derive(Serialize)]
+struct CommentContext<'a> {
+    diff: &'a str,
+    services: Vec<CommentService<'a>>,
+    unmapped_files: &'a [String],
+    ignored_files: &'a [String],
+    has_baseline: bool,
+}
+
+#[derive(Serialize)]
+struct CommentService<'a> {
+    name: &'a str,
+    count: usize,
+    files: &'a [String],
+    owners: Vec<String>,
+    deprecated: bool,
+    replacement: Option<&'a str>,
+    new: bool,
+}
+
+/// Renders a user-supplied Handlebars template for the Action comment.
+fn render_comment_template(
+    path: &Path,
+    diff: &str,
+    impacted_services: &BTreeMap<String, Vec<String>>,
+    unmapped_files: &[String],
+    ignored_files: &[String],
+    services: Option<&ServicesFile>,
+    baseline: Option<&HashSet<String>>,
+) -> Result<String> {
+    let template = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read comment template at {:?}", path))?;
+
+    let names: Vec<&String> = impacted_services.keys().collect();
+    let services_ctx: Vec<CommentService> = names
+        .into_iter()
+        .map(|svc| {
+            let files = &impacted_services[svc];
+            let def = services.and_then(|s| s.get(svc));
+            let owners: Vec<String> = def
+                .and_then(|def| def.owners.as_ref())
+                .map(|o| o.iter().map(owner_label).collect())
+                .unwrap_or_default();
+            CommentService {
+                name: svc,
+                count: files.len(),
+                files,
+                owners,
+                deprecated: def.is_some_and(|d| d.is_deprecated()),
+                replacement: def.and_then(|d| d.deprecated_in_favor_of.as_deref()),
+                new: baseline.is_some_and(|b| !b.contains(svc.as_str())),
+            }
+        })
+        .collect();
+    let ctx = CommentContext {
+        diff,
+        services: services_ctx,
+        unmapped_files,
+        ignored_files,
+        has_baseline: baseline.is_some(),
+    };
+
+    let mut hb = handlebars::Handlebars::new();
+    // Output is Markdown, not HTML.
+    hb.register_escape_fn(handlebars::no_escape);
+    hb.render_template(&template, &ctx)
+        .with_context(|| format!("Failed to render comment template {:?}", path))
+}
+
+/// Replaces a previous report once a PR no longer impacts anything.
+const NO_IMPACT_COMMENT: &str =
+    "### 🧭 ServiceOwners Impact Report\n\n_No services impacted by the latest changes._";
+
+/// Appends the footer, then the markers `post_pr_comment` finds the comment by.
+fn finish_comment(mut body: String, footer: Option<&str>) -> String {
+    if let Some(footer) = footer.filter(|f| !f.trim().is_empty()) {
+        body.truncate(body.trim_end().len());
+        body.push_str("\n\n");
+        body.push_str(footer);
+    }
+    body.push_str("\n<!-- serviceowners:begin -->\n<!-- serviceowners:end -->");
+    body
+}
+
+/// Fills `{repo}` and `{commit}` in `--comment-footer`.
+fn expand_footer(footer: &str) -> String {
+    let env = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+    let repo = env("GITHUB_REPOSITORY")
+        .or_else(|| {
+            Some(format!(
+                "{}/{}",
+                env("BITBUCKET_PROJECT")?,
+                env("BITBUCKET_REPO_SLUG")?
+            ))
+        })
+        .unwrap_or_default();
+    let commit = env("GITHUB_SHA")
+        .or_else(|| env("BITBUCKET_COMMIT"))
+        .or_else(|| {
+            let output = Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .output()
+                .ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_default();
+    footer.replace("{repo}", &repo).replace("{commit}", &commit)
+}
+
+/// Creates or updates the marker comment. When `create` is false only an
+/// existing comment is updated.
+fn post_pr_comment(backend: &dyn CommentBackend, body: &str, create: bool) -> Result<()> {
+    match backend.find_existing("<!-- serviceowners:begin -->")? {
+        Some(id) => {
+            backend.update(&id, body)?;
+            println!("Updated comment {}", id);
+        }
+        None if !create => println!("Nothing impacted; skipping comment on {}", backend.target()),
+        None => {
+            backend.create(body)?;
+            println!("Created comment on {}", backend.target());
+        }
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walker_visits_unicode_paths_as_written() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(dir.path().join("src/café")).unwrap();
+        std::fs::write(dir.path().join("src/café/naïve.rs"), "fn main() {}\n").unwrap();
+        let mut seen = Vec::new();
+        walk_dir(dir.path(), &ProgressBar::hidden(), false, |path| {
+            seen.push(path.to_string());
+            true
+        });
+        assert_eq!(seen, ["src/café/naïve.rs"]);
+
+        let mapper = ServiceMapper::parse("src/café/** café\n").unwrap();
+        assert_eq!(mapper.find_service(&seen[0]), Some("café"));
+    }
+
+    const LINT_RULES: &str = "\
+src/pay/** pay
+src/web/** web
+";
+
+    const LINT_SERVICES: &str = "
+services:
+  payments:
+    aliases: [pay]
+    docs: ftp://docs.example.com/payments
+  billing: {}
+";
+
+    /// The `services_diagnostics` for the lint fixtures with `code`
+    fn lint_diagnostics(code: &str, strict: bool) -> Vec<Diagnostic> {
+        let mapper = ServiceMapper::parse(LINT_RULES).unwrap();
+        let services: ServicesFile = serde_yaml::from_str(LINT_SERVICES).unwrap();
+        let diags = services_diagnostics(
+            &mapper,
+            &services,
+            Path::new("services.yaml"),
+            strict,
+            true,
+            None,
+        );
+        assert!(serviceowners::DIAGNOSTIC_CODES
+            .iter()
+            .any(|(c, _)| *c == code));
+        diags.into_iter().filter(|d| d.code == code).collect()
+    }
+
+    fn code_line_severity(diags: &[Diagnostic]) -> Vec<(&str, Option<usize>, Severity)> {
+        diags.iter().map(|d| (d.code, d.line, d.severity)).collect()
+    }
+
+    #[test]
+    fn diagnostic_alias() {
+        let diags = lint_diagnostics("alias", true);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("alias", Some(1), Severity::Warning)]
+        );
+    }
+
+    #[test]
+    fn diagnostic_undefined_service() {
+        let diags = lint_diagnostics("undefined-service", false);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("undefined-service", Some(2), Severity::Warning)]
+        );
+        let diags = lint_diagnostics("undefined-service", true);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("undefined-service", Some(2), Severity::Error)]
+        );
+    }
+
+    #[test]
+    fn diagnostic_unused_service() {
+        let diags = lint_diagnostics("unused-service", false);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("unused-service", None, Severity::Warning)]
+        );
+        assert!(diags[0].message.contains("'billing'"));
+        let diags = lint_diagnostics("unused-service", true);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("unused-service", None, Severity::Error)]
+        );
+    }
+
+    #[test]
+    fn diagnostic_link() {
+        let diags = lint_diagnostics("link", true);
+        assert_eq!(
+            code_line_severity(&diags),
+            [("link", None, Severity::Warning)]
+        );
+        assert!(diags[0].message.contains("'payments' docs"));
+    }
+
+    #[test]
+    fn diagnostic_unused_pattern() {
+        // Tests run from the crate root, which has a src/ but no nowhere/
+        let mapper = ServiceMapper::parse("src/** core\nnowhere/** ghost\n").unwrap();
+        let unused = unused_rules(&mapper, true, false);
+        assert_eq!(unused, [1]);
+        // Every file it matches is excluded, but the glob still matches
+        let mapper = ServiceMapper::parse("src/** core\nsrc/** ghost\n!src/** ghost\n").unwrap();
+        assert_eq!(mapper.find_service("src/main.rs"), Some("core"));
+        assert!(unused_rules(&mapper, true, false).is_empty());
+        let mapper = ServiceMapper::parse("src/** core\nnowhere/** ghost\n").unwrap();
+        let unused = unused_rules(&mapper, true, false);
+        let diag = unused_pattern(&mapper, unused[0]);
+        assert_eq!(
+            code_line_severity(&[diag]),
+            [("unused-pattern", Some(2), Severity::Warning)]
+        );
+    }
+
+    #[test]
+    fn lint_fix_is_refused_under_scope() {
+        for args in [
+            &[
+                "sowners",
+                "--scope",
+                "a",
+                "lint",
+                "--check-matches",
+                "--fix",
+            ][..],
+            &[
+                "sowners",
+                "lint",
+                "--check-matches",
+                "--fix",
+                "--scope",
+                "a",
+            ],
+        ] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            let err = check_fix_allowed(&cli).unwrap_err();
+            assert!(err.to_string().contains("--scope a"), "{}", err);
+        }
+        let cli = Cli::try_parse_from(["sowners", "lint", "--check-matches", "--fix"]).unwrap();
+        assert!(check_fix_allowed(&cli).is_ok());
+    }
+
+    /// `git diff --raw -z` output, split on NUL as `git_paths` returns it
+    fn raw_fields(raw: &str) -> Vec<String> {
+        raw.split('\0')
+            .filter(|f| !f.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    const OLD_SHA: &str = "1111111111111111111111111111111111111111";
+    const NEW_SHA: &str = "2222222222222222222222222222222222222222";
+
+    #[test]
+    fn submodule_only_diff_has_no_changed_files() {
+        let raw = format!(
+            ":160000 160000 {} {} M\0vendor/lib\0:000000 160000 {} {} A\0vendor/new\0",
+            OLD_SHA,
+            NEW_SHA,
+            "0".repeat(40),
+            NEW_SHA
+        );
+        let files = parse_raw_diff(raw_fields(&raw), DiffOptions::default(), None);
+        assert!(files.is_empty(), "{:?}", files);
+
+        let opts = DiffOptions {
+            include_submodules: true,
+            ..DiffOptions::default()
+        };
+        let files = parse_raw_diff(raw_fields(&raw), opts, None);
+        assert_eq!(
+            files,
+            [
+                ("vendor/lib".to_string(), FileStatus::Modified),
+                ("vendor/new".to_string(), FileStatus::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn submodule_updates_are_dropped_next_to_file_changes() {
+        let raw = format!(
+            ":160000 160000 {o} {n} M\0vendor/lib\0\
+             :100644 100644 {o} {n} R090\0src/old.rs\0src/new.rs\0\
+             :100644 100644 {o} {n} M\0src/café.rs\0",
+            o = OLD_SHA,
+            n = NEW_SHA
+        );
+        let files = parse_raw_diff(raw_fields(&raw), DiffOptions::default(), None);
+        assert_eq!(
+            files,
+            [
+                ("src/old.rs".to_string(), FileStatus::RenamedFrom),
+                ("src/new.rs".to_string(), FileStatus::Renamed),
+                ("src/café.rs".to_string(), FileStatus::Modified),
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_impact_serializes_as_sha_and_services() {
+        let row = CommitImpact {
+            sha: "abc123".to_string(),
+            services: vec!["api".to_string(), "web".to_string()],
+        };
+        assert_eq!(
+            serde_json::to_value(&row).unwrap(),
+            serde_json::json!({ "sha": "abc123", "services": ["api", "web"] })
+        );
+    }
+
+    #[test]
+    fn working_tree_deletions_need_include_deleted() {
+        let entries = raw_fields(
+            " M src/lib.rs\0 D src/gone.rs\0D  src/staged_gone.rs\0\
+             R  src/new.rs\0src/old.rs\0?? notes.md\0",
+        );
+        assert_eq!(
+            parse_status(entries.clone(), DiffOptions::default()),
+            ["src/lib.rs", "src/new.rs", "notes.md"]
+        );
+        let opts = DiffOptions {
+            include_deleted: true,
+            ..DiffOptions::default()
+        };
+        assert_eq!(
+            parse_status(entries, opts),
+            [
+                "src/lib.rs",
+                "src/gone.rs",
+                "src/staged_gone.rs",
+                "src/new.rs",
+                "notes.md"
+            ]
+        );
+    }
+
+    #[test]
+    fn why_unmapped_report_keeps_the_unmapped_shape() {
+        let mapper = ServiceMapper::parse("src/api/** api\n").unwrap();
+        let misses = mapper.near_misses("src/web/x.ts", 3);
+        let report = WhyUnmappedReport::Unmapped {
+            schema_version: JSON_SCHEMA_VERSION,
+            path: "src/web/x.ts".to_string(),
+            service: None,
+            out_of_scope: None,
+            ignored: false,
+            near_misses: misses.iter().map(NearMissRow::from).collect(),
+            suggested_rule: "src/web/** <service>".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "schema_version": JSON_SCHEMA_VERSION,
+                "path": "src/web/x.ts",
+                "service": null,
+                "out_of_scope": null,
+                "ignored": false,
+                "near_misses": [{
+                    "pattern": "src/api/**",
+                    "service": "api",
+                    "line": 1,
+                    "source": null,
+                    "shared_segments": 1,
+                }],
+                "suggested_rule": "src/web/** <service>",
+            })
+        );
+    }
+
+    #[test]
+    fn explain_diff_row_lists_runners_up_after_the_winner() {
+        let mapper = ServiceMapper::parse("src/** core\nsrc/api/** api\n").unwrap();
+        let row = ExplainDiffRow::new("src/api/x.rs", &mapper.ranked_matches("src/api/x.rs"));
+        assert_eq!(
+            serde_json::to_value(&row).unwrap(),
+            serde_json::json!({
+                "path": "src/api/x.rs",
+                "service": "api",
+                "pattern": "src/api/**",
+                "line": 2,
+                "source": null,
+                "fallback": false,
+                "runner_up": [{"service": "core", "pattern": "src/**", "line": 1, "source": null}],
+            })
+        );
+        let row = ExplainDiffRow::new("docs/x.md", &mapper.ranked_matches("docs/x.md"));
+        assert_eq!((row.service, row.runner_up.len()), (None, 0));
+    }
+
+    #[test]
+    fn next_page_link_follows_rel_next() {
+        let link = "<https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=2>; rel=\"next\", \
+                    <https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=5>; rel=\"last\"";
+        assert_eq!(
+            next_page_link(link).as_deref(),
+            Some("https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=2")
+        );
+        let last_page = "<https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=1>; rel=\"first\", \
+                         <https://api.github.com/repositories/1/issues/2/comments?per_page=100&page=4>; rel=\"prev\"";
+        assert_eq!(next_page_link(last_page), None);
+        assert_eq!(next_page_link(""), None);
+    }
+
+    #[test]
+    fn renames_and_copies_in_a_raw_diff() {
+        let raw = format!(
+            ":100644 100644 {o} {n} R100\0api/old.rs\0web/new\tname.rs\0\
+             :100644 100644 {o} {n} C075\0api/base.rs\0api/line\nbreak.rs\0\
+             :100644 100644 {o} {n} R100\0api/a.rs\0api/b.rs\0\
+             :100644 100644 {o} {n} M\0web/x.rs\0",
+            o = OLD_SHA,
+            n = NEW_SHA
+        );
+        let entries = parse_raw_diff(raw_fields(&raw), DiffOptions::default(), None);
+        assert_eq!(
+            entries,
+            [
+                ("api/old.rs".to_string(), FileStatus::RenamedFrom),
+                ("web/new\tname.rs".to_string(), FileStatus::Renamed),
+                ("api/line\nbreak.rs".to_string(), FileStatus::Copied),
+                ("api/a.rs".to_string(), FileStatus::RenamedFrom),
+                ("api/b.rs".to_string(), FileStatus::Renamed),
+                ("web/x.rs".to_string(), FileStatus::Modified),
+            ]
+        );
+
+        let mapper = ServiceMapper::parse("api/** api\nweb/** web\n").unwrap();
+        let mut files: Vec<String> = entries
+            .into_iter()
+            .filter(|(_, status)| *status != FileStatus::RenamedFrom)
+            .map(|(path, _)| path)
+            .collect();
+        let renames = vec![
+            ("api/old.rs".to_string(), "web/new\tname.rs".to_string()),
+            ("api/a.rs".to_string(), "api/b.rs".to_string()),
+        ];
+        let (within, across) = split_renames(&mapper, None, renames, &mut files);
+        assert_eq!(
+            files,
+            [
+                "web/new\tname.rs",
+                "api/line\nbreak.rs",
+                "web/x.rs",
+                "api/old.rs"
+            ]
+        );
+        assert_eq!(within.len(), 1);
+        assert_eq!(
+            (within[0].from.as_str(), within[0].to.as_str()),
+            ("api/a.rs", "api/b.rs")
+        );
+        assert_eq!(across.len(), 1);
+        assert_eq!(across[0].from_service.as_deref(), Some("api"));
+        assert_eq!(across[0].to_service.as_deref(), Some("web"));
+    }
+
+    #[test]
+    fn numstat_rows_give_the_changed_paths() {
+        let fields = raw_fields(
+            "3\t1\tsrc/lib.rs\0-\t-\tassets/logo.png\0\
+             0\t0\t\0src/old.rs\0src/new.rs\0\
+             2\t0\tdocs/tab\there.md\0",
+        );
+        let paths = parse_numstat(fields);
+        let mut paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "assets/logo.png",
+                "docs/tab\there.md",
+                "src/lib.rs",
+                "src/new.rs"
+            ]
+        );
+        assert!(parse_numstat(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn unmapped_files_group_by_top_level_directory() {
+        let files: Vec<String> = [
+            "tools/a.sh",
+            "Makefile",
+            "docs/x.md",
+            "tools/deep/b.sh",
+            "docs/y.md",
+            "LICENSE",
+            "scripts/c.py",
+        ]
+        .iter()
+        .map(|f| f.to_string())
+        .collect();
+        assert_eq!(
+            group_unmapped_files(&files),
+            [
+                ("./".to_string(), 2),
+                ("docs/".to_string(), 2),
+                ("tools/".to_string(), 2),
+                ("scripts/".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn no_unmapped_files_means_no_groups() {
+        assert!(group_unmapped_files(&[]).is_empty());
+    }
+
+    #[test]
+    fn comment_template_sees_services_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("comment.hbs");
+        std::fs::write(
+            &path,
+            "{{diff}}\n{{#each services}}{{name}} {{count}}{{#if new}} new{{/if}}{{#if deprecated}} -> {{replacement}}{{/if}}: {{#each owners}}{{this}} {{/each}}\n{{/each}}unmapped: {{#each unmapped_files}}{{this}}{{/each}}",
+        )
+        .unwrap();
+        let services: ServicesFile = serde_yaml::from_str(
+            "services:\n  api:\n    owners: [\"@acme/api\"]\n  old:\n    deprecated: true\n    deprecated_in_favor_of: api\n",
+        )
+        .unwrap();
+        let mut impacted = BTreeMap::new();
+        impacted.insert("api".to_string(), vec!["api/a.rs".to_string()]);
+        impacted.insert(
+            "old".to_string(),
+            vec!["old/a.rs".to_string(), "old/b.rs".to_string()],
+        );
+        let baseline: HashSet<String> = ["old".to_string()].into_iter().collect();
+        let body = render_comment_template(
+            &path,
+            "main...HEAD",
+            &impacted,
+            &["README.md".to_string()],
+            &[],
+            Some(&services),
+            Some(&baseline),
+        )
+        .unwrap();
+        assert_eq!(
+            body,
+            "main...HEAD\napi 1 new: @acme/api \nold 2 -> api: \nunmapped: README.md"
+        );
+    }
+
+    #[test]
+    fn comment_template_errors_name_the_template() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("comment.hbs");
+        let render = |path: &Path| {
+            render_comment_template(
+                path,
+                "HEAD~1...HEAD",
+                &BTreeMap::new(),
+                &[],
+                &[],
+                None,
+                None,
+            )
+        };
+        let err = render(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to read comment template"));
+        std::fs::write(&path, "{{#each services}}unclosed").unwrap();
+        let err = render(&path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Failed to render comment template"));
+    }
+}